@@ -0,0 +1,83 @@
+//! Benchmarks for url matching and building.  Run them with
+//! `cargo bench --bench routing`.
+
+extern crate hyper;
+extern crate pen;
+
+use std::time::{Duration, Instant};
+
+use hyper::method::Method;
+use pen::ViewArgs;
+use pen::routing::{IntoMatcher, Map, Rule};
+
+const ITERATIONS: u32 = 20_000;
+
+fn build_map(resources: usize) -> Map {
+    let mut map = Map::new();
+    for i in 0..resources {
+        let rules = [
+            (format!("/resource{}/", i), vec![Method::Get], format!("resource{}.index", i)),
+            (format!("/resource{}/<id:int>", i), vec![Method::Get], format!("resource{}.show", i)),
+            (format!("/resource{}/<id:int>", i), vec![Method::Put, Method::Delete], format!("resource{}.update", i)),
+            (format!("/resource{}/<name>/edit", i), vec![Method::Get, Method::Post], format!("resource{}.edit", i)),
+            (format!("/resource{}/files/<file:path>", i), vec![Method::Get], format!("resource{}.file", i)),
+        ];
+        for (rule, methods, endpoint) in &rules {
+            let matcher = rule.into_matcher(&map);
+            map.add(Rule::new(matcher, methods, endpoint));
+        }
+    }
+    map.build_index();
+    map
+}
+
+fn bench<F: FnMut()>(name: &str, mut f: F) {
+    for _ in 0..ITERATIONS / 10 {
+        f();
+    }
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let elapsed = start.elapsed();
+    let nanos = elapsed.as_secs() * 1_000_000_000 + u64::from(elapsed.subsec_nanos());
+    println!("{:<40} {:>10} ns/iter", name, nanos / u64::from(ITERATIONS));
+}
+
+fn bench_match(map: &Map, name: &str, path: &str, method: Method) {
+    bench(name, || {
+        let adapter = map.bind("localhost".to_owned(), path.to_owned(), None, method.clone());
+        adapter.matched();
+    });
+}
+
+fn main() {
+    for &resources in &[10, 100] {
+        let rule_count = resources * 5;
+        let map = build_map(resources);
+        let last = resources - 1;
+        println!("{} rules", rule_count);
+        bench_match(&map, "match first static", "/resource0/", Method::Get);
+        bench_match(&map, "match last static", &format!("/resource{}/", last), Method::Get);
+        bench_match(&map, "match last int", &format!("/resource{}/42", last), Method::Get);
+        bench_match(&map, "match last path", &format!("/resource{}/files/a/b/c.txt", last), Method::Get);
+        bench_match(&map, "redirect last branch", &format!("/resource{}", last), Method::Get);
+        bench_match(&map, "method not allowed", &format!("/resource{}/42", last), Method::Post);
+        bench_match(&map, "not found", "/missing/page", Method::Get);
+        bench("allowed methods", || {
+            let adapter = map.bind("localhost".to_owned(), format!("/resource{}/42", last), None, Method::Options);
+            adapter.allowed_methods();
+        });
+        let mut values = ViewArgs::new();
+        values.insert("id", 42);
+        bench("build last", || {
+            let adapter = map.bind("localhost".to_owned(), "/".to_owned(), None, Method::Get);
+            adapter.build(&format!("resource{}.show", last), &values, None, false).unwrap();
+        });
+        let start = Instant::now();
+        build_map(resources);
+        let elapsed: Duration = start.elapsed();
+        println!("{:<40} {:>10} us", "build map and index", elapsed.as_secs() * 1_000_000 + u64::from(elapsed.subsec_micros()));
+        println!();
+    }
+}
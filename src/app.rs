@@ -1,10 +1,10 @@
-use std::convert::Into;
 use std::fmt;
 use std::collections::HashMap;
 use std::error::Error;
 use std::fs::File;
 use std::path::PathBuf;
 use std::net::ToSocketAddrs;
+use std::sync::Mutex;
 
 use hyper;
 use hyper::method::Method;
@@ -13,15 +13,18 @@ use hyper::server::Request as HTTPRequest;
 use hyper::server::Response as HTTPResponse;
 
 use types::{
+    ViewArgs,
     PenError,
         PenHTTPError,
         PenUserError,
+        PenFormError,
 
     UserError,
     PenResult,
     ViewFunc,
     HTTPErrorHandler,
     UserErrorHandler,
+    FormErrorHandler,
     BeforeRequestFunc,
     AfterRequestFunc,
     TeardownRequestFunc,
@@ -29,11 +32,20 @@ use types::{
 use wrappers::{
     Request,
     Response,
+    Responder,
 };
+use formparser::FormError;
 use helpers::{PathBound, send_from_directory_range, redirect};
 use serving::run_server;
-use routing::{Map, Rule, Matcher};
-use http_errors::{HTTPError, NotFound, InternalServerError};
+use module::Module;
+use routing::{Map, Rule, HostRule, Matcher, IntoMatcher, Converter, BuildError, RouteConflict};
+use config::Config;
+use templating::TemplateEngine;
+use middleware::{Middleware, Next};
+use sessions::{SessionStore, save_session, save_flashes};
+#[cfg(feature = "templates")] use templating::HandlebarsEngine;
+#[cfg(feature = "openapi")] use openapi::{Operation, Operations};
+use http_errors::{HTTPError, NotFound, InternalServerError, RequestEntityTooLarge};
 use typemap::ShareMap;
 
 const DEFAULT_THREADS: usize = 15;
@@ -44,66 +56,196 @@ pub struct Pen {
     pub static_folder: String,
     pub static_url_path: String,
     pub template_folder: String,
+    pub config: Config,
     pub extensions: ShareMap,
     pub url_map: Map,
-    view_functions: HashMap<String, ViewFunc>,
+    view_functions: HashMap<String, Box<ViewFunc>>,
+    duplicate_endpoints: Vec<String>,
     before_request_funcs: Vec<Box<BeforeRequestFunc>>,
     after_request_funcs: Vec<Box<AfterRequestFunc>>,
     teardown_request_funcs: Vec<Box<TeardownRequestFunc>>,
+    middlewares: Vec<(Option<String>, Box<Middleware>)>,
+    pub(crate) max_content_lengths: HashMap<String, u64>,
+    #[cfg(feature = "openapi")]
+    pub(crate) operations: Operations,
     http_error_handlers: HashMap<u16, Box<HTTPErrorHandler>>,
     user_error_handlers: HashMap<String, Box<UserErrorHandler>>,
+    form_error_handler: Option<Box<FormErrorHandler>>,
+    pub(crate) modules: HashMap<String, Module>,
+    pub(crate) template_engine: Option<Box<TemplateEngine>>,
+    pub(crate) session_store: Option<Box<SessionStore>>,
+    pub(crate) last_session_sweep: Mutex<u64>,
 }
 
 impl Pen {
     pub fn new(root_path: &str) -> Pen {
+        let mut config = Config::new();
+        config.set("DEBUG", false);
+        config.set("TESTING", false);
+        config.set("PREFERRED_URL_SCHEME", "http");
         Pen {
             root_path: root_path.to_string(),
             name: root_path.to_string(),
             static_folder: String::from("static"),
             static_url_path: String::from("/static"),
             template_folder: String::from("templates"),
+            config: config,
             extensions: ShareMap::custom(),
             url_map: Map::new(),
             view_functions: HashMap::new(),
+            duplicate_endpoints: vec![],
             before_request_funcs: vec![],
             after_request_funcs: vec![],
             teardown_request_funcs: vec![],
+            middlewares: vec![],
+            max_content_lengths: HashMap::new(),
+            #[cfg(feature = "openapi")]
+            operations: HashMap::new(),
             http_error_handlers: HashMap::new(),
             user_error_handlers: HashMap::new(),
+            form_error_handler: None,
+            modules: HashMap::new(),
+            template_engine: default_template_engine(),
+            session_store: None,
+            last_session_sweep: Mutex::new(0),
         }
     }
 
-    pub fn is_debug(&self) -> bool { false }
-    pub fn is_testing(&self) -> bool { false }
+    pub fn is_debug(&self) -> bool { self.config.get_boolean("DEBUG", false) }
+    pub fn is_testing(&self) -> bool { self.config.get_boolean("TESTING", false) }
 
-    pub fn route<M: Into<Matcher>, N: AsRef<[Method]>>(&mut self, rule: M, methods: N, endpoint: &str, view_func: ViewFunc) {
-        self.add_url_rule(rule.into(), methods.as_ref(), endpoint, view_func);
+    /// Build an absolute url outside of a request, using the
+    /// `SERVER_NAME` and `PREFERRED_URL_SCHEME` config values.
+    pub fn external_url_for(&self, endpoint: &str, values: &ViewArgs) -> Result<String, BuildError> {
+        let server_name: String = match self.config.get("SERVER_NAME") {
+            Some(server_name) => server_name,
+            None => return Err(BuildError::new(endpoint, None)),
+        };
+        let mut url_adapter = self.url_map.bind(server_name.clone(), String::from("/"), None, Method::Get);
+        url_adapter.set_url_scheme(&self.config.get_or("PREFERRED_URL_SCHEME", String::from("http")));
+        url_adapter.set_server_name(Some(server_name));
+        url_adapter.build(endpoint, values, None, true)
+    }
+
+    pub fn route<M: IntoMatcher, N: AsRef<[Method]>, F: Fn(&mut Request) -> R + Send + Sync + 'static, R: Responder + 'static>(&mut self, rule: M, methods: N, endpoint: &str, view_func: F) {
+        let matcher = rule.into_matcher(&self.url_map);
+        self.add_url_rule(matcher, methods.as_ref(), endpoint, view_func);
     }
 
-    pub fn get<M: Into<Matcher>>(&mut self, rule: M, endpoint: &str, view_func: ViewFunc) {
+    pub fn get<M: IntoMatcher, F: Fn(&mut Request) -> R + Send + Sync + 'static, R: Responder + 'static>(&mut self, rule: M, endpoint: &str, view_func: F) {
         self.route(rule, &[Method::Get], endpoint, view_func);
     }
 
-    pub fn post<M: Into<Matcher>>(&mut self, rule: M, endpoint: &str, view_func: ViewFunc) {
+    pub fn post<M: IntoMatcher, F: Fn(&mut Request) -> R + Send + Sync + 'static, R: Responder + 'static>(&mut self, rule: M, endpoint: &str, view_func: F) {
         self.route(rule, &[Method::Post], endpoint, view_func);
     }
 
-    pub fn delete<M: Into<Matcher>>(&mut self, rule: M, endpoint: &str, view_func: ViewFunc) {
+    pub fn delete<M: IntoMatcher, F: Fn(&mut Request) -> R + Send + Sync + 'static, R: Responder + 'static>(&mut self, rule: M, endpoint: &str, view_func: F) {
         self.route(rule, &[Method::Delete], endpoint, view_func);
     }
 
-    pub fn patch<M: Into<Matcher>>(&mut self, rule: M, endpoint: &str, view_func: ViewFunc) {
+    pub fn patch<M: IntoMatcher, F: Fn(&mut Request) -> R + Send + Sync + 'static, R: Responder + 'static>(&mut self, rule: M, endpoint: &str, view_func: F) {
         self.route(rule, &[Method::Patch], endpoint, view_func);
     }
 
-    pub fn put<M: Into<Matcher>>(&mut self, rule: M, endpoint: &str, view_func: ViewFunc) {
+    pub fn put<M: IntoMatcher, F: Fn(&mut Request) -> R + Send + Sync + 'static, R: Responder + 'static>(&mut self, rule: M, endpoint: &str, view_func: F) {
         self.route(rule, &[Method::Put], endpoint, view_func);
     }
 
-    pub fn add_url_rule(&mut self, matcher: Matcher, methods: &[Method], endpoint: &str, view_func: ViewFunc) {
-        let url_rule = Rule::new(matcher, methods, endpoint);
+    /// Like `route`, but the rule only matches requests to the host.  The
+    /// host may contain variables like `<tenant>.example.com`, they are
+    /// passed to the view with the other view args.
+    pub fn route_host<M: IntoMatcher, N: AsRef<[Method]>, F: Fn(&mut Request) -> R + Send + Sync + 'static, R: Responder + 'static>(&mut self, host: &str, rule: M, methods: N, endpoint: &str, view_func: F) {
+        let host_rule = HostRule::host(host, &self.url_map);
+        let url_rule = Rule::new(rule.into_matcher(&self.url_map), methods.as_ref(), endpoint).with_host(host_rule);
+        self.add_boxed_rule(url_rule, Box::new(move |request| view_func(request).respond()));
+    }
+
+    /// Like `route_host`, but for a subdomain of the `SERVER_NAME` config
+    /// value like `<tenant>` or `api`.
+    pub fn route_subdomain<M: IntoMatcher, N: AsRef<[Method]>, F: Fn(&mut Request) -> R + Send + Sync + 'static, R: Responder + 'static>(&mut self, subdomain: &str, rule: M, methods: N, endpoint: &str, view_func: F) {
+        let host_rule = HostRule::subdomain(subdomain, &self.url_map);
+        let url_rule = Rule::new(rule.into_matcher(&self.url_map), methods.as_ref(), endpoint).with_host(host_rule);
+        self.add_boxed_rule(url_rule, Box::new(move |request| view_func(request).respond()));
+    }
+
+    /// Add another rule to an endpoint that already has a view function,
+    /// for example to serve it under an old url too.  Panics if the
+    /// endpoint has no view function.
+    pub fn add_rule<M: IntoMatcher, N: AsRef<[Method]>>(&mut self, rule: M, methods: N, endpoint: &str) {
+        if !self.view_functions.contains_key(endpoint) {
+            panic!("endpoint {} has no view function", endpoint);
+        }
+        let matcher = rule.into_matcher(&self.url_map);
+        self.url_map.add(Rule::new(matcher, methods.as_ref(), endpoint));
+    }
+
+    pub fn add_url_rule<F: Fn(&mut Request) -> R + Send + Sync + 'static, R: Responder + 'static>(&mut self, matcher: Matcher, methods: &[Method], endpoint: &str, view_func: F) {
+        self.add_boxed_url_rule(matcher, methods, endpoint, Box::new(move |request| view_func(request).respond()));
+    }
+
+    pub(crate) fn add_boxed_url_rule(&mut self, matcher: Matcher, methods: &[Method], endpoint: &str, view_func: Box<ViewFunc>) {
+        self.add_boxed_rule(Rule::new(matcher, methods, endpoint), view_func);
+    }
+
+    fn add_boxed_rule(&mut self, url_rule: Rule, view_func: Box<ViewFunc>) {
+        let endpoint = url_rule.endpoint.clone();
         self.url_map.add(url_rule);
-        self.view_functions.insert(endpoint.to_string(), view_func);
+        if self.view_functions.insert(endpoint.clone(), view_func).is_some() {
+            self.duplicate_endpoints.push(endpoint);
+        }
+    }
+
+    /// Print the routing table, see `Map::routing_table`.
+    pub fn print_routes(&self) {
+        print!("{}", self.url_map.routing_table());
+    }
+
+    /// Check the routes for endpoints registered with more than one view
+    /// function and for rules shadowed by other rules, see
+    /// `Map::validate`.  `run` panics if this fails.
+    pub fn validate_routes(&self) -> Result<(), Vec<RouteConflict>> {
+        let mut conflicts: Vec<RouteConflict> = self.duplicate_endpoints.iter()
+            .map(|endpoint| RouteConflict::DuplicateEndpoint(endpoint.clone()))
+            .collect();
+        if let Err(ambiguous) = self.url_map.validate() {
+            conflicts.extend(ambiguous);
+        }
+        if conflicts.is_empty() { Ok(()) } else { Err(conflicts) }
+    }
+
+    /// Register a url converter, see `Map::add_converter`.
+    pub fn register_converter<C: Converter + 'static>(&mut self, name: &str, converter: C) {
+        self.url_map.add_converter(name, converter);
+    }
+
+    /// Override `MAX_CONTENT_LENGTH` for the requests to an endpoint.
+    pub fn set_max_content_length(&mut self, endpoint: &str, max_content_length: u64) {
+        self.max_content_lengths.insert(endpoint.to_string(), max_content_length);
+    }
+
+    /// Describe all methods of an endpoint in the OpenAPI document.
+    #[cfg(feature = "openapi")]
+    pub fn describe(&mut self, endpoint: &str, operation: Operation) {
+        self.operations.insert((endpoint.to_string(), None), operation);
+    }
+
+    /// Describe one method of an endpoint in the OpenAPI document, this
+    /// takes precedence over `describe`.
+    #[cfg(feature = "openapi")]
+    pub fn describe_method(&mut self, endpoint: &str, method: Method, operation: Operation) {
+        self.operations.insert((endpoint.to_string(), Some(method)), operation);
+    }
+
+    /// Set the engine used by `render_template`.
+    pub fn set_template_engine<E: TemplateEngine + 'static>(&mut self, engine: E) {
+        self.template_engine = Some(Box::new(engine));
+    }
+
+    /// Keep sessions on the server in the given store instead of a signed
+    /// cookie.
+    pub fn set_session_store<S: SessionStore + 'static>(&mut self, store: S) {
+        self.session_store = Some(Box::new(store));
     }
 
     pub fn enable_static_file_handling(&mut self) {
@@ -111,6 +253,39 @@ impl Pen {
         self.route(&rule as &str, &[Method::Get], "static", send_app_static_file);
     }
 
+    /// Register a module.  Its routes are added under the given url
+    /// prefix and its endpoints are namespaced by the module name.
+    pub fn register_module(&mut self, mut module: Module, url_prefix: &str) {
+        if self.modules.contains_key(&module.name) {
+            panic!("a module named {} is already registered", module.name);
+        }
+        module.register_routes(self, url_prefix);
+        self.modules.insert(module.name.clone(), module);
+    }
+
+    /// Add a middleware to the stack, it wraps the middlewares added
+    /// after it.
+    pub fn add_middleware<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.middlewares.push((None, Box::new(middleware)));
+    }
+
+    /// Add a middleware that only applies to requests routed to an
+    /// endpoint starting with the prefix, for example `api.` for the
+    /// endpoints of the `api` module.
+    pub fn add_middleware_for<M: Middleware + 'static>(&mut self, endpoint_prefix: &str, middleware: M) {
+        self.middlewares.push((Some(endpoint_prefix.to_string()), Box::new(middleware)));
+    }
+
+    fn get_module(&self, module_name: Option<String>) -> Option<&Module> {
+        match module_name {
+            Some(name) => self.modules.get(&name),
+            None => None,
+        }
+    }
+
+    /// Run a function before each request.  The functions of the app run
+    /// before those of the module handling the request, the first one
+    /// returning a result ends the request.
     pub fn before_request<F: Fn(&mut Request) -> Option<PenResult> + Send + Sync + 'static>(&mut self, f: F) {
         self.before_request_funcs.push(Box::new(f));
     }
@@ -131,6 +306,13 @@ impl Pen {
         self.user_error_handlers.insert(error_desc.to_string(), Box::new(f));
     }
 
+    /// Register the handler for form errors returned from views, for
+    /// example with `request.try_form()?`.  Without a handler form errors
+    /// become the matching HTTP error.
+    pub fn register_form_error_handler<F: Fn(FormError) -> PenResult + Send + Sync + 'static>(&mut self, f: F) {
+        self.form_error_handler = Some(Box::new(f));
+    }
+
     pub fn httperrorhandler<F: Fn(HTTPError) -> PenResult + Send + Sync + 'static>(&mut self, status_code: u16, f: F) {
         self.register_http_error_handler(status_code, f);
     }
@@ -145,6 +327,13 @@ impl Pen {
                 return Some(result);
             }
         }
+        if let Some(module) = self.get_module(request.module_name()) {
+            for func in &module.before_request_funcs {
+                if let Some(result) = func(request) {
+                    return Some(result);
+                }
+            }
+        }
         None
     }
 
@@ -159,9 +348,17 @@ impl Pen {
             Ok(default_options_response)
         }
         else {
-            match self.view_functions.get(&request.endpoint().unwrap()) {
-                Some(&view_func) => view_func(request),
+            let result = match self.view_functions.get(&request.endpoint().unwrap()) {
+                Some(view_func) => view_func(request),
                 None => Err(PenHTTPError(NotFound)),
+            };
+            // A view failing on a body that is too large may report the
+            // read error, the client has to get 413 either way.  Form
+            // errors are kept for the form error handler.
+            match result {
+                Err(PenFormError(FormError::SizeExceeded)) => result,
+                _ if request.body_too_large() => Err(PenHTTPError(RequestEntityTooLarge)),
+                _ => result,
             }
         }
     }
@@ -179,27 +376,57 @@ impl Pen {
     }
 
     fn process_response(&self, request: &Request, response: &mut Response) {
+        if let Some(module) = self.get_module(request.module_name()) {
+            for func in module.after_request_funcs.iter().rev() { func(request, response); }
+        }
         for func in self.after_request_funcs.iter().rev() { func(request, response); }
+        save_session(request, response);
+        save_flashes(request, response);
     }
 
-    fn do_teardown_request(&self, e: Option<&PenError>) {
+    fn do_teardown_request(&self, request: &Request, e: Option<&PenError>) {
+        if let Some(module) = self.get_module(request.module_name()) {
+            for func in module.teardown_request_funcs.iter().rev() { func(e); }
+        }
         for func in self.teardown_request_funcs.iter().rev() { func(e); }
     }
 
-    fn handle_all_error(&self, e: PenError) -> PenResult {
+    fn handle_all_error(&self, request: &Request, e: PenError) -> PenResult {
         match e {
-            PenHTTPError(e) => self.handle_http_error(e),
-            PenUserError(e) => self.handle_user_error(e),
+            PenHTTPError(e) => self.handle_http_error(request, e),
+            PenUserError(e) => self.handle_user_error(request, e),
+            PenFormError(e) => self.handle_form_error(request, e),
+        }
+    }
+
+    fn handle_form_error(&self, request: &Request, e: FormError) -> PenResult {
+        if let Some(module) = self.get_module(request.module_name()) {
+            if let Some(ref handler) = module.form_error_handler {
+                return handler(e);
+            }
         }
+        if let Some(ref handler) = self.form_error_handler {
+            handler(e)
+        } else { self.handle_http_error(request, e.to_http_error()) }
     }
 
-    fn handle_user_error(&self, e: UserError) -> PenResult {
+    fn handle_user_error(&self, request: &Request, e: UserError) -> PenResult {
+        if let Some(module) = self.get_module(request.module_name()) {
+            if let Some(handler) = module.user_error_handlers.get(&e.desc) {
+                return handler(e);
+            }
+        }
         if let Some(handler) = self.user_error_handlers.get(&e.desc) {
             handler(e)
         } else { Err(PenUserError(e)) }
     }
 
-    fn handle_http_error(&self, e: HTTPError) -> PenResult {
+    fn handle_http_error(&self, request: &Request, e: HTTPError) -> PenResult {
+        if let Some(module) = self.get_module(request.module_name()) {
+            if let Some(handler) = module.http_error_handlers.get(&e.code()) {
+                return handler(e);
+            }
+        }
         if let Some(handler) = self.http_error_handlers.get(&e.code()) {
             handler(e)
         } else { Ok(e.to_response()) }
@@ -207,48 +434,89 @@ impl Pen {
 
     fn handle_error(&self, request: &Request, e: &PenError) -> Response {
         self.log_error(request, e);
-        if let Ok(response) = self.handle_http_error(InternalServerError) {
+        if let Ok(response) = self.handle_http_error(request, InternalServerError) {
             response
         } else {
             InternalServerError.to_response()
         }
     }
 
+    /// The response for an error from the error handlers, or a 500
+    /// response if none handles it.  For middlewares that have to add
+    /// headers to error responses too.
+    pub(crate) fn make_error_response(&self, request: &Request, e: PenError) -> Response {
+        match self.handle_all_error(request, e) {
+            Ok(response) => response,
+            Err(e) => {
+                if self.is_testing() {
+                    panic!("Error on {} [{}]: {}", request.path(), request.method(), e);
+                }
+                self.handle_error(request, &e)
+            },
+        }
+    }
+
     fn log_error(&self, request: &Request, e: &PenError) {
         eprintln!("Error on {} [{}]: {}", request.path(), request.method(), e.description());
     }
 
-    fn full_dispatch_request(&self, request: &mut Request) -> Result<Response, PenError> {
-        let result = match self.preprocess_request(request) {
+    /// Run the middlewares that apply to the request, the app middlewares
+    /// first and then the middlewares of the module.  Requests with a too
+    /// large `Content-Length` are rejected before anything reads the body.
+    fn run_middlewares(&self, request: &mut Request) -> PenResult {
+        if request.exceeds_max_content_length() {
+            return Err(PenHTTPError(RequestEntityTooLarge));
+        }
+        let endpoint = request.endpoint();
+        let mut middlewares: Vec<&Middleware> = self.middlewares.iter()
+            .filter(|(prefix, _)| match (prefix, &endpoint) {
+                (Some(prefix), Some(endpoint)) => endpoint.starts_with(prefix.as_str()),
+                (Some(_), None) => false,
+                (None, _) => true,
+            })
+            .map(|(_, middleware)| &**middleware)
+            .collect();
+        if let Some(module) = self.get_module(request.module_name()) {
+            middlewares.extend(module.middlewares.iter().map(|middleware| &**middleware));
+        }
+        Next::new(self, &middlewares).run(request)
+    }
+
+    /// Run the `before_request` functions and the view function.  Errors
+    /// are returned as they are, so middlewares can see and translate them.
+    pub(crate) fn full_dispatch_request(&self, request: &mut Request) -> PenResult {
+        match self.preprocess_request(request) {
             Some(result) => result,
             None => self.dispatch_request(request),
-        };
-        let rv = match result {
-            Ok(response) => Ok(response),
-            Err(e) => self.handle_all_error(e),
-        };
-        match rv {
-            Ok(mut response) => {
-                self.process_response(request, &mut response);
-                Ok(response)
-            },
-            Err(e) => Err(e),
         }
     }
 
+    /// Handle a request.  Errors left by the middlewares are passed to the
+    /// error handlers once, then the `after_request` functions run on the
+    /// final response.
     pub fn handle_request(&self, request: &mut Request) -> Response {
         request.match_request();
-        match self.full_dispatch_request(request) {
-            Ok(response) => {
-                self.do_teardown_request(None);
-                response
-            },
+        let result = match self.run_middlewares(request) {
+            Ok(response) => Ok(response),
+            Err(e) => self.handle_all_error(request, e),
+        };
+        let (mut response, error) = match result {
+            Ok(response) => (response, None),
             Err(e) => {
-                let response = self.handle_error(request, &e);
-                self.do_teardown_request(Some(&e));
-                response
-            }
-        }
+                // In testing mode unhandled errors fail the test instead of
+                // becoming a 500 response.
+                if self.is_testing() {
+                    self.do_teardown_request(request, Some(&e));
+                    request.remove_temp_files();
+                    panic!("Error on {} [{}]: {}", request.path(), request.method(), e);
+                }
+                (self.handle_error(request, &e), Some(e))
+            },
+        };
+        self.process_response(request, &mut response);
+        self.do_teardown_request(request, error.as_ref());
+        request.remove_temp_files();
+        response
     }
 
     pub fn run<A: ToSocketAddrs>(self, addr: A) {
@@ -297,8 +565,244 @@ impl fmt::Debug for Pen {
     }
 }
 
+#[cfg(feature = "templates")]
+fn default_template_engine() -> Option<Box<TemplateEngine>> {
+    Some(Box::new(HandlebarsEngine::new()))
+}
+
+#[cfg(not(feature = "templates"))]
+fn default_template_engine() -> Option<Box<TemplateEngine>> {
+    None
+}
+
 fn send_app_static_file(request: &mut Request) -> PenResult {
     let mut static_path = PathBuf::from(&request.app.root_path);
     static_path.push(&request.app.static_folder);
     send_from_directory_range(static_path.to_str().unwrap(), &request.view_args["filename"], false, request.headers().get())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use hyper::method::Method;
+    use regex::Regex;
+
+    use datastructures::ViewArgs;
+    use module::Module;
+    use routing::RouteConflict;
+    use testing::{Client, TestRequest};
+    use types::{PenResult, UserError};
+    use wrappers::Request;
+    use super::Pen;
+
+    fn view(_: &mut Request) -> &'static str {
+        "view"
+    }
+
+    #[test]
+    fn test_validate_routes() {
+        let mut app = Pen::new("/web/pen");
+        app.get("/", "index", view);
+        app.get("/users/<id:int>", "user", view);
+        assert!(app.validate_routes().is_ok());
+
+        app.get("/users/<user_id:int>", "other_user", view);
+        app.post("/home", "index", view);
+        let conflicts = app.validate_routes().unwrap_err();
+        assert_eq!(conflicts.len(), 2);
+        match conflicts[0] {
+            RouteConflict::DuplicateEndpoint(ref endpoint) => assert_eq!(endpoint, "index"),
+            ref conflict => panic!("unexpected conflict {}", conflict),
+        }
+        match conflicts[1] {
+            RouteConflict::Ambiguous { ref endpoint, ref shadowed_by, .. } => {
+                assert_eq!(endpoint, "other_user");
+                assert_eq!(shadowed_by, "user");
+            },
+            ref conflict => panic!("unexpected conflict {}", conflict),
+        }
+    }
+
+    #[test]
+    fn test_add_rule() {
+        let mut app = Pen::new("/web/pen");
+        app.get("/", "index", view);
+        app.add_rule("/home", &[Method::Get, Method::Post], "index");
+        assert!(app.validate_routes().is_ok());
+
+        let mut client = Client::new(&app);
+        assert_eq!(client.get("/").text(), "view");
+        assert_eq!(client.get("/home").text(), "view");
+        assert_eq!(client.post("/home", "").text(), "view");
+        assert_eq!(client.post("/", "").status_code, 405);
+    }
+
+    #[test]
+    #[should_panic(expected = "endpoint index has no view function")]
+    fn test_add_rule_without_view() {
+        Pen::new("/web/pen").add_rule("/", &[Method::Get], "index");
+    }
+
+    #[test]
+    fn test_before_request_order() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut app = Pen::new("/web/pen");
+        let app_calls = calls.clone();
+        app.before_request(move |request| {
+            app_calls.lock().unwrap().push("app");
+            if request.path() == "/admin/blocked" { Some(Ok("blocked".into())) } else { None }
+        });
+        let mut module = Module::new("admin", "/web/pen");
+        let module_calls = calls.clone();
+        module.before_request(move |_| {
+            module_calls.lock().unwrap().push("module");
+            None
+        });
+        module.get("/", "index", view);
+        module.get("/blocked", "blocked", view);
+        app.register_module(module, "/admin");
+
+        let mut client = Client::new(&app);
+        assert_eq!(client.get("/admin/").text(), "view");
+        assert_eq!(*calls.lock().unwrap(), vec!["app", "module"]);
+        calls.lock().unwrap().clear();
+        assert_eq!(client.get("/admin/blocked").text(), "blocked");
+        assert_eq!(*calls.lock().unwrap(), vec!["app"]);
+    }
+
+    fn failing_view(_: &mut Request) -> PenResult {
+        Err(UserError::new("failed").into())
+    }
+
+    #[test]
+    fn test_unhandled_error() {
+        let mut app = Pen::new("/web/pen");
+        app.get("/", "index", failing_view);
+        assert_eq!(Client::new(&app).get("/").status_code, 500);
+    }
+
+    #[test]
+    #[should_panic(expected = "Error on / [GET]: failed")]
+    fn test_unhandled_error_in_testing_mode() {
+        let mut app = Pen::new("/web/pen");
+        app.config.set("TESTING", true);
+        app.get("/", "index", failing_view);
+        Client::new(&app).get("/");
+    }
+
+    fn get_host(app: &Pen, host: &str, path: &str) -> String {
+        Client::new(app).open(TestRequest::new(Method::Get, path).header("Host", host)).text()
+    }
+
+    fn host_app() -> Pen {
+        let mut app = Pen::new("/web/pen");
+        app.config.set("SERVER_NAME", "example.com");
+        app.route_subdomain("<tenant>", "/", &[Method::Get], "tenant", |request: &mut Request| {
+            let tenant: &String = request.view_args.get("tenant").unwrap();
+            format!("tenant {}", tenant)
+        });
+        app.route_subdomain("", "/", &[Method::Get], "index", view);
+        app.route_host("api<version:int>.shop.net", "/items/<id:int>", &[Method::Get], "item", |request: &mut Request| {
+            let version: &i64 = request.view_args.get("version").unwrap();
+            let id: &i64 = request.view_args.get("id").unwrap();
+            format!("item {} v{}", id, version)
+        });
+        app.route_host("localhost:8000", "/", &[Method::Get], "dev", |_: &mut Request| "dev");
+        app.get("/", "fallback", |_: &mut Request| "fallback");
+        app
+    }
+
+    #[test]
+    fn test_host_matching() {
+        let app = host_app();
+        assert_eq!(get_host(&app, "acme.example.com", "/"), "tenant acme");
+        assert_eq!(get_host(&app, "a.b.example.com", "/"), "tenant a.b");
+        assert_eq!(get_host(&app, "example.com", "/"), "view");
+        assert_eq!(get_host(&app, "api2.shop.net", "/items/7"), "item 7 v2");
+        assert_eq!(get_host(&app, "apix.shop.net", "/"), "fallback");
+        assert_eq!(get_host(&app, "example.org", "/"), "fallback");
+    }
+
+    #[test]
+    fn test_host_port_stripping() {
+        let app = host_app();
+        assert_eq!(get_host(&app, "Acme.Example.com:8080", "/"), "tenant acme");
+        assert_eq!(get_host(&app, "api2.shop.net:443", "/items/7"), "item 7 v2");
+        assert_eq!(get_host(&app, "localhost:8000", "/"), "dev");
+        assert_eq!(get_host(&app, "localhost:9000", "/"), "fallback");
+        assert_eq!(get_host(&app, "localhost", "/"), "fallback");
+    }
+
+    #[test]
+    fn test_build_host_urls() {
+        let mut app = host_app();
+        app.get("/links", "links", |request: &mut Request| {
+            let mut own = ViewArgs::new();
+            own.insert("tenant", "acme".to_string());
+            let mut other = ViewArgs::new();
+            other.insert("tenant", "other".to_string());
+            let mut item = ViewArgs::new();
+            item.insert("version", 2);
+            item.insert("id", 7);
+            let urls = [
+                request.url_for("tenant", &own).unwrap(),
+                request.url_for("tenant", &other).unwrap(),
+                request.url_for("index", &ViewArgs::new()).unwrap(),
+                request.url_for("item", &item).unwrap(),
+            ];
+            urls.join(" ")
+        });
+        assert_eq!(get_host(&app, "acme.example.com:8080", "/links"),
+                   "/ http://other.example.com/ http://example.com/ http://api2.shop.net/items/7");
+
+        let mut values = ViewArgs::new();
+        values.insert("tenant", "acme".to_string());
+        assert_eq!(app.external_url_for("tenant", &values).unwrap(), "http://acme.example.com/");
+        assert!(app.external_url_for("tenant", &ViewArgs::new()).is_err());
+    }
+
+    #[test]
+    fn test_url_for() {
+        let mut app = Pen::new("/web/pen");
+        app.config.set("PREFERRED_URL_SCHEME", "https");
+        app.get("/users/<name>", "user", view);
+        let mut module = Module::new("admin", "/web/pen");
+        module.get("/", "index", view);
+        module.get("/links", "links", |request: &mut Request| {
+            let mut values = ViewArgs::new();
+            values.insert("name", "a b".to_string());
+            values.insert("tab", "x&y".to_string());
+            let urls = [
+                request.url_for(".index", &ViewArgs::new()).unwrap(),
+                request.url_for("admin.index", &ViewArgs::new()).unwrap(),
+                request.url_for("user", &values).unwrap(),
+                request.external_url_for("user", &values).unwrap(),
+            ];
+            urls.join(" ")
+        });
+        app.register_module(module, "/admin");
+
+        assert_eq!(get_host(&app, "example.com:8080", "/admin/links"),
+                   "/admin/ /admin/ /users/a%20b?tab=x%26y https://example.com:8080/users/a%20b?tab=x%26y");
+    }
+
+    #[test]
+    fn test_module_rules() {
+        let mut module = Module::new("blog", "/web/pen");
+        module.get(format!("/{}", "posts"), "posts", |_: &mut Request| "posts");
+        module.get("/posts/<id:int>", "post", |_: &mut Request| "post");
+        module.get(Regex::new(r"^/archive/(?P<year>\d{4})$").unwrap(), "archive", |request: &mut Request| {
+            request.view_args.get::<String>("year").unwrap().clone()
+        });
+        let mut app = Pen::new("/web/pen");
+        app.register_module(module, "/blog");
+
+        let mut client = Client::new(&app);
+        assert_eq!(client.get("/blog/posts").text(), "posts");
+        assert_eq!(client.get("/blog/posts/3").text(), "post");
+        assert_eq!(client.get("/blog/archive/2017").text(), "2017");
+        assert_eq!(client.get("/archive/2017").status_code, 404);
+        assert_eq!(client.get("/other/blog/archive/2017").status_code, 404);
+    }
+}
@@ -0,0 +1,311 @@
+//! This module implements configuration related stuff.
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::collections::btree_map;
+use std::env;
+use std::fmt;
+#[cfg(any(feature = "json", feature = "toml"))] use std::fs::File;
+#[cfg(any(feature = "json", feature = "toml"))] use std::io::{self, Read};
+#[cfg(any(feature = "json", feature = "toml"))] use std::path::Path;
+
+#[cfg(feature = "json")] use serde::Serialize;
+#[cfg(feature = "json")] use serde_json;
+#[cfg(feature = "toml")] use toml;
+
+/// A configuration value.  Values loaded from environment variables
+/// are strings, they are parsed when read with a typed getter.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigValue {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+}
+
+impl fmt::Display for ConfigValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigValue::Bool(value) => write!(f, "{}", value),
+            ConfigValue::Int(value) => write!(f, "{}", value),
+            ConfigValue::Float(value) => write!(f, "{}", value),
+            ConfigValue::String(ref value) => f.write_str(value),
+        }
+    }
+}
+
+impl From<bool> for ConfigValue {
+    fn from(value: bool) -> ConfigValue { ConfigValue::Bool(value) }
+}
+
+impl From<i64> for ConfigValue {
+    fn from(value: i64) -> ConfigValue { ConfigValue::Int(value) }
+}
+
+/// Values above `i64::MAX` are saturated, they mean "unlimited" for the
+/// size and time limits anyway.
+impl From<u64> for ConfigValue {
+    fn from(value: u64) -> ConfigValue { ConfigValue::Int(cmp::min(value, i64::MAX as u64) as i64) }
+}
+
+impl From<usize> for ConfigValue {
+    fn from(value: usize) -> ConfigValue { ConfigValue::from(value as u64) }
+}
+
+impl From<f64> for ConfigValue {
+    fn from(value: f64) -> ConfigValue { ConfigValue::Float(value) }
+}
+
+impl From<String> for ConfigValue {
+    fn from(value: String) -> ConfigValue { ConfigValue::String(value) }
+}
+
+impl<'a> From<&'a str> for ConfigValue {
+    fn from(value: &'a str) -> ConfigValue { ConfigValue::String(value.to_owned()) }
+}
+
+/// Types that can be read from a `ConfigValue`.
+pub trait FromConfigValue: Sized {
+    fn from_config_value(value: &ConfigValue) -> Option<Self>;
+}
+
+impl FromConfigValue for bool {
+    fn from_config_value(value: &ConfigValue) -> Option<bool> {
+        match *value {
+            ConfigValue::Bool(value) => Some(value),
+            ConfigValue::Int(value) => Some(value != 0),
+            ConfigValue::String(ref value) => match value.to_lowercase().as_str() {
+                "1" | "true" | "yes" | "on" => Some(true),
+                "0" | "false" | "no" | "off" | "" => Some(false),
+                _ => None,
+            },
+            ConfigValue::Float(_) => None,
+        }
+    }
+}
+
+impl FromConfigValue for i64 {
+    fn from_config_value(value: &ConfigValue) -> Option<i64> {
+        match *value {
+            ConfigValue::Int(value) => Some(value),
+            ConfigValue::String(ref value) => value.trim().parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+impl FromConfigValue for u64 {
+    fn from_config_value(value: &ConfigValue) -> Option<u64> {
+        match *value {
+            ConfigValue::Int(value) if value >= 0 => Some(value as u64),
+            ConfigValue::String(ref value) => value.trim().parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+impl FromConfigValue for usize {
+    fn from_config_value(value: &ConfigValue) -> Option<usize> {
+        u64::from_config_value(value).map(|value| value as usize)
+    }
+}
+
+impl FromConfigValue for f64 {
+    fn from_config_value(value: &ConfigValue) -> Option<f64> {
+        match *value {
+            ConfigValue::Float(value) => Some(value),
+            ConfigValue::Int(value) => Some(value as f64),
+            ConfigValue::String(ref value) => value.trim().parse().ok(),
+            ConfigValue::Bool(_) => None,
+        }
+    }
+}
+
+impl FromConfigValue for String {
+    fn from_config_value(value: &ConfigValue) -> Option<String> {
+        Some(value.to_string())
+    }
+}
+
+/// The application configuration.  Well known keys are:
+///
+/// - `DEBUG`: enable debug mode
+/// - `TESTING`: enable testing mode, errors without a handler panic
+///   instead of being turned into a 500 response
+/// - `SECRET_KEY`: the key used to sign cookies
+/// - `SECRET_KEY_FALLBACKS`: comma separated old keys which are still
+///   accepted when verifying signed cookies
+/// - `SESSION_COOKIE_NAME`, `SESSION_COOKIE_PATH`, `SESSION_COOKIE_DOMAIN`,
+///   `SESSION_COOKIE_SECURE`, `SESSION_COOKIE_HTTPONLY`,
+///   `SESSION_COOKIE_SAMESITE`: the session cookie
+/// - `PERMANENT_SESSION_LIFETIME`: the lifetime of sessions in seconds
+/// - `SESSION_REFRESH_EACH_REQUEST`: send the cookie of permanent sessions
+///   with every response so they expire after the last request, true by
+///   default
+/// - `FLASH_COOKIE_NAME`: the cookie flashed messages are kept in
+/// - `SESSION_IDLE_TIMEOUT`: the seconds after which unused sessions are
+///   removed from the session store
+/// - `SESSION_SWEEP_INTERVAL`: the seconds between two sweeps of the
+///   session store
+/// - `MAX_CONTENT_LENGTH`: the maximum size of a request body in bytes,
+///   it can be overridden per endpoint with `Pen::set_max_content_length`
+/// - `JSON_MAX_LENGTH`: the maximum size of a body read with
+///   `Request::json` in bytes, one MiB by default
+/// - `MAX_UPLOAD_FILE_SIZE`: the maximum size of a file read with
+///   `Request::multipart`
+/// - `UPLOAD_TEMP_DIR`: the directory of temporary upload files, the
+///   system's temporary directory by default
+/// - `SERVER_NAME`: the name and port of the server, used to build
+///   external urls outside of requests and to match subdomain rules
+/// - `PREFERRED_URL_SCHEME`: the scheme used to build external urls
+#[derive(Clone, Debug)]
+pub struct Config {
+    config: BTreeMap<String, ConfigValue>,
+}
+
+impl Default for Config {
+    fn default() -> Config { Config::new() }
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            config: BTreeMap::new(),
+        }
+    }
+
+    pub fn set<V: Into<ConfigValue>>(&mut self, key: &str, value: V) {
+        self.config.insert(key.to_owned(), value.into());
+    }
+
+    pub fn get_value(&self, key: &str) -> Option<&ConfigValue> {
+        self.config.get(key)
+    }
+
+    /// Get a value converted to the requested type.  Returns `None` if
+    /// the key is missing or can not be converted.
+    pub fn get<T: FromConfigValue>(&self, key: &str) -> Option<T> {
+        self.config.get(key).and_then(T::from_config_value)
+    }
+
+    pub fn get_or<T: FromConfigValue>(&self, key: &str, default: T) -> T {
+        self.get(key).unwrap_or(default)
+    }
+
+    pub fn get_boolean(&self, key: &str, default: bool) -> bool {
+        self.get_or(key, default)
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<ConfigValue> {
+        self.config.remove(key)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.config.contains_key(key)
+    }
+
+    pub fn iter(&self) -> btree_map::Iter<String, ConfigValue> {
+        self.config.iter()
+    }
+
+    /// Load all environment variables starting with the prefix.  The
+    /// prefix is stripped, so with prefix `MYAPP_` the variable
+    /// `MYAPP_DEBUG` sets the key `DEBUG`.
+    pub fn load_env(&mut self, prefix: &str) {
+        for (key, value) in env::vars() {
+            if let Some(name) = key.strip_prefix(prefix) {
+                if !name.is_empty() {
+                    self.config.insert(name.to_owned(), ConfigValue::String(value));
+                }
+            }
+        }
+    }
+
+    /// Load the top level keys of a JSON object file.  Values other
+    /// than booleans, numbers and strings are ignored.
+    #[cfg(feature = "json")]
+    pub fn load_json_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let content = read_file(path.as_ref())?;
+        let value: serde_json::Value = serde_json::from_str(&content)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.load_json_value(value)
+    }
+
+    /// Load the fields of a serializable struct, for example one that was
+    /// deserialized from a custom source.  Use `#[serde(rename_all =
+    /// "SCREAMING_SNAKE_CASE")]` to get the usual key names.
+    #[cfg(feature = "json")]
+    pub fn load_object<T: Serialize>(&mut self, object: &T) -> io::Result<()> {
+        let value = serde_json::to_value(object)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.load_json_value(value)
+    }
+
+    #[cfg(feature = "json")]
+    fn load_json_value(&mut self, value: serde_json::Value) -> io::Result<()> {
+        let object = match value {
+            serde_json::Value::Object(object) => object,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "config must be an object")),
+        };
+        for (key, value) in object {
+            let value = match value {
+                serde_json::Value::Bool(value) => ConfigValue::Bool(value),
+                serde_json::Value::Number(ref number) if number.is_i64() =>
+                    ConfigValue::Int(number.as_i64().unwrap()),
+                serde_json::Value::Number(ref number) =>
+                    ConfigValue::Float(number.as_f64().unwrap_or(0.0)),
+                serde_json::Value::String(value) => ConfigValue::String(value),
+                _ => continue,
+            };
+            self.config.insert(key, value);
+        }
+        Ok(())
+    }
+
+    /// Load the top level keys of a TOML file.  Values other than
+    /// booleans, numbers and strings are ignored.
+    #[cfg(feature = "toml")]
+    pub fn load_toml_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let content = read_file(path.as_ref())?;
+        let value: toml::Value = content.parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let table = match value {
+            toml::Value::Table(table) => table,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "config must be a table")),
+        };
+        for (key, value) in table {
+            let value = match value {
+                toml::Value::Boolean(value) => ConfigValue::Bool(value),
+                toml::Value::Integer(value) => ConfigValue::Int(value),
+                toml::Value::Float(value) => ConfigValue::Float(value),
+                toml::Value::String(value) => ConfigValue::String(value),
+                _ => continue,
+            };
+            self.config.insert(key, value);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(any(feature = "json", feature = "toml"))]
+fn read_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, ConfigValue};
+
+    #[test]
+    fn test_large_unsigned_values() {
+        let mut config = Config::new();
+        config.set("MAX_CONTENT_LENGTH", u64::MAX);
+        assert_eq!(config.get_value("MAX_CONTENT_LENGTH"), Some(&ConfigValue::Int(i64::MAX)));
+        assert_eq!(config.get::<u64>("MAX_CONTENT_LENGTH"), Some(i64::MAX as u64));
+        config.set("MAX_UPLOAD_FILE_SIZE", 1u64 << 40);
+        assert_eq!(config.get::<u64>("MAX_UPLOAD_FILE_SIZE"), Some(1 << 40));
+    }
+}
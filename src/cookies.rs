@@ -0,0 +1,248 @@
+//! This module implements cookie parsing and building of `Set-Cookie`
+//! header values.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::header::HttpDate;
+use time;
+use url::percent_encoding::{EncodeSet, percent_decode, utf8_percent_encode};
+
+/// The bytes that are not allowed in a cookie value, plus `%` so encoded
+/// values can be decoded again.
+#[derive(Clone, Copy)]
+struct CookieEncodeSet;
+
+impl EncodeSet for CookieEncodeSet {
+    fn contains(&self, byte: u8) -> bool {
+        !(0x21..=0x7e).contains(&byte) || b"\",;\\%".contains(&byte)
+    }
+}
+
+/// Parse a cookie header entry `name=value` and decode the value.
+pub fn parse_cookie(cookie: &str) -> Option<(String, String)> {
+    let index = cookie.find('=')?;
+    let name = cookie[..index].trim();
+    if name.is_empty() {
+        return None;
+    }
+    let mut value = cookie[index + 1..].trim();
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        value = &value[1..value.len() - 1];
+    }
+    Some((name.to_owned(), percent_decode(value.as_bytes()).decode_utf8_lossy().into_owned()))
+}
+
+/// The `SameSite` attribute of a cookie.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl fmt::Display for SameSite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SameSite::Strict => f.write_str("Strict"),
+            SameSite::Lax => f.write_str("Lax"),
+            SameSite::None => f.write_str("None"),
+        }
+    }
+}
+
+impl FromStr for SameSite {
+    type Err = ();
+
+    /// Parse the attribute value, case insensitive.
+    fn from_str(value: &str) -> Result<SameSite, ()> {
+        match value.to_lowercase().as_str() {
+            "strict" => Ok(SameSite::Strict),
+            "lax" => Ok(SameSite::Lax),
+            "none" => Ok(SameSite::None),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A cookie to be set with `Response::add_cookie`.  The value is percent
+/// encoded, `Request::cookie` decodes it again.
+#[derive(Clone, Debug)]
+pub struct CookieBuilder {
+    name: String,
+    value: String,
+    max_age: Option<u64>,
+    expires: Option<SystemTime>,
+    path: Option<String>,
+    domain: Option<String>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+impl CookieBuilder {
+    pub fn new(name: &str, value: &str) -> CookieBuilder {
+        CookieBuilder {
+            name: name.to_owned(),
+            value: value.to_owned(),
+            max_age: None,
+            expires: None,
+            path: None,
+            domain: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    /// The number of seconds until the cookie expires.
+    pub fn max_age(mut self, seconds: u64) -> CookieBuilder {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    pub fn expires(mut self, expires: SystemTime) -> CookieBuilder {
+        self.expires = Some(expires);
+        self
+    }
+
+    pub fn path(mut self, path: &str) -> CookieBuilder {
+        self.path = Some(path.to_owned());
+        self
+    }
+
+    pub fn domain(mut self, domain: &str) -> CookieBuilder {
+        self.domain = Some(domain.to_owned());
+        self
+    }
+
+    pub fn secure(mut self, secure: bool) -> CookieBuilder {
+        self.secure = secure;
+        self
+    }
+
+    pub fn http_only(mut self, http_only: bool) -> CookieBuilder {
+        self.http_only = http_only;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> CookieBuilder {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    /// Make the cookie expire immediately, which deletes it on the client.
+    pub fn expired(self) -> CookieBuilder {
+        self.max_age(0).expires(UNIX_EPOCH)
+    }
+}
+
+fn http_date(date: SystemTime) -> HttpDate {
+    let seconds = date.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+    HttpDate(time::at_utc(time::Timespec::new(seconds as i64, 0)))
+}
+
+impl fmt::Display for CookieBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.name, utf8_percent_encode(&self.value, CookieEncodeSet))?;
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age)?;
+        }
+        if let Some(expires) = self.expires {
+            write!(f, "; Expires={}", http_date(expires))?;
+        }
+        if let Some(ref path) = self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if let Some(ref domain) = self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+        if self.secure {
+            f.write_str("; Secure")?;
+        }
+        if self.http_only {
+            f.write_str("; HttpOnly")?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={}", same_site)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use hyper::header::SetCookie;
+
+    use wrappers::Response;
+    use super::{CookieBuilder, SameSite, parse_cookie};
+
+    fn pair(name: &str, value: &str) -> Option<(String, String)> {
+        Some((name.to_owned(), value.to_owned()))
+    }
+
+    #[test]
+    fn test_parse_cookie() {
+        assert_eq!(parse_cookie("name=value"), pair("name", "value"));
+        assert_eq!(parse_cookie(" name = value "), pair("name", "value"));
+        assert_eq!(parse_cookie("name=a=b"), pair("name", "a=b"));
+        assert_eq!(parse_cookie("name="), pair("name", ""));
+        assert_eq!(parse_cookie("=value"), None);
+        assert_eq!(parse_cookie("value"), None);
+    }
+
+    #[test]
+    fn test_parse_cookie_decoding() {
+        assert_eq!(parse_cookie("name=x%20y%3Bz%25"), pair("name", "x y;z%"));
+        assert_eq!(parse_cookie("name=caf%C3%A9"), pair("name", "café"));
+        assert_eq!(parse_cookie("name=\"quoted\""), pair("name", "quoted"));
+        assert_eq!(parse_cookie("name=\"a%2Cb\""), pair("name", "a,b"));
+        assert_eq!(parse_cookie("name=\""), pair("name", "\""));
+    }
+
+    #[test]
+    fn test_encoding_roundtrip() {
+        let value = "a \"b\", c; d\\e%f é";
+        let cookie = CookieBuilder::new("name", value).to_string();
+        assert_eq!(cookie, "name=a%20%22b%22%2C%20c%3B%20d%5Ce%25f%20%C3%A9");
+        assert_eq!(parse_cookie(&cookie), pair("name", value));
+    }
+
+    #[test]
+    fn test_attributes() {
+        let cookie = CookieBuilder::new("name", "value")
+            .max_age(60)
+            .expires(UNIX_EPOCH + Duration::from_secs(1_500_000_000))
+            .path("/admin")
+            .domain("example.com")
+            .secure(true)
+            .http_only(true)
+            .same_site(SameSite::Strict);
+        assert_eq!(cookie.to_string(), "name=value; Max-Age=60; Expires=Fri, 14 Jul 2017 02:40:00 GMT; \
+                                        Path=/admin; Domain=example.com; Secure; HttpOnly; SameSite=Strict");
+        assert_eq!(CookieBuilder::new("name", "value").to_string(), "name=value");
+    }
+
+    #[test]
+    fn test_same_site() {
+        let cookie = CookieBuilder::new("name", "value").secure(true).same_site(SameSite::None);
+        assert_eq!(cookie.to_string(), "name=value; Secure; SameSite=None");
+        assert_eq!("none".parse(), Ok(SameSite::None));
+        assert_eq!("LAX".parse(), Ok(SameSite::Lax));
+        assert_eq!("Strict".parse(), Ok(SameSite::Strict));
+        assert_eq!("always".parse::<SameSite>(), Err(()));
+    }
+
+    #[test]
+    fn test_delete_cookie() {
+        let mut response = Response::new_empty();
+        response.add_cookie(CookieBuilder::new("kept", "1"));
+        response.delete_cookie("session");
+        let cookies = &response.headers.get::<SetCookie>().unwrap().0;
+        assert_eq!(cookies[0], "kept=1");
+        assert_eq!(cookies[1], "session=; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT; Path=/");
+    }
+}
@@ -0,0 +1,340 @@
+//! This module implements Cross-Origin Resource Sharing as a middleware.
+//! It answers preflight requests and adds the `Access-Control-*` headers
+//! to the responses of allowed origins, error responses included.
+
+use hyper::method::Method;
+use regex::{self, Regex};
+
+use middleware::{Middleware, Next};
+use types::PenResult;
+use wrappers::{Request, Response};
+
+enum Origin {
+    Any,
+    Exact(String),
+    Pattern(Regex),
+}
+
+impl Origin {
+    fn matches(&self, origin: &str) -> bool {
+        match *self {
+            Origin::Any => true,
+            Origin::Exact(ref allowed) => allowed == origin,
+            Origin::Pattern(ref regex) => regex.is_match(origin),
+        }
+    }
+}
+
+/// The CORS middleware.  Add it with `Pen::add_middleware`, or with
+/// `Pen::add_middleware_for` to only allow cross origin requests to some
+/// endpoints.
+///
+/// ```ignore
+/// app.add_middleware(Cors::new()
+///     .allow_origin("https://*.example.com")
+///     .allow_headers(&["Content-Type"])
+///     .supports_credentials(true));
+/// ```
+pub struct Cors {
+    origins: Vec<Origin>,
+    methods: Option<Vec<Method>>,
+    allowed_headers: Option<Vec<String>>,
+    exposed_headers: Vec<String>,
+    supports_credentials: bool,
+    max_age: Option<u64>,
+}
+
+impl Default for Cors {
+    fn default() -> Cors { Cors::new() }
+}
+
+impl Cors {
+    /// Create a CORS middleware.  Until origins are added all origins are
+    /// allowed, the methods of the matched route are allowed and the
+    /// requested headers are allowed.
+    pub fn new() -> Cors {
+        Cors {
+            origins: Vec::new(),
+            methods: None,
+            allowed_headers: None,
+            exposed_headers: Vec::new(),
+            supports_credentials: false,
+            max_age: None,
+        }
+    }
+
+    /// Allow an origin like `https://example.com`.  A `*` matches any
+    /// part of the origin, so `https://*.example.com` allows all
+    /// subdomains and `*` allows all origins.
+    pub fn allow_origin(mut self, origin: &str) -> Cors {
+        let origin = if origin == "*" {
+            Origin::Any
+        } else if origin.contains('*') {
+            let parts: Vec<String> = origin.split('*').map(regex::escape).collect();
+            Origin::Pattern(Regex::new(&format!("^{}$", parts.join("[^/]*"))).unwrap())
+        } else {
+            Origin::Exact(origin.to_string())
+        };
+        self.origins.push(origin);
+        self
+    }
+
+    /// Allow all origins matching the regex.
+    pub fn allow_origin_regex(mut self, regex: Regex) -> Cors {
+        self.origins.push(Origin::Pattern(regex));
+        self
+    }
+
+    /// Only allow these methods instead of all methods of the route.
+    pub fn allow_methods(mut self, methods: &[Method]) -> Cors {
+        self.methods = Some(methods.to_vec());
+        self
+    }
+
+    /// Only allow these request headers instead of all requested ones.
+    pub fn allow_headers(mut self, headers: &[&str]) -> Cors {
+        self.allowed_headers = Some(headers.iter().map(|header| header.to_string()).collect());
+        self
+    }
+
+    /// Response headers the client may read in addition to the simple ones.
+    pub fn expose_headers(mut self, headers: &[&str]) -> Cors {
+        self.exposed_headers = headers.iter().map(|header| header.to_string()).collect();
+        self
+    }
+
+    /// Allow requests with cookies and authorization headers.
+    pub fn supports_credentials(mut self, supports_credentials: bool) -> Cors {
+        self.supports_credentials = supports_credentials;
+        self
+    }
+
+    /// The number of seconds a preflight response may be cached.
+    pub fn max_age(mut self, seconds: u64) -> Cors {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    fn is_allowed_origin(&self, origin: &str) -> bool {
+        self.origins.is_empty() || self.origins.iter().any(|allowed| allowed.matches(origin))
+    }
+
+    fn allows_any_origin(&self) -> bool {
+        self.origins.is_empty() || self.origins.iter().any(|allowed| matches!(*allowed, Origin::Any))
+    }
+
+    fn set_origin_headers(&self, response: &mut Response, origin: &str) {
+        if self.allows_any_origin() && !self.supports_credentials {
+            set_header(response, "Access-Control-Allow-Origin", "*");
+        } else {
+            set_header(response, "Access-Control-Allow-Origin", origin);
+            add_vary(response, "Origin");
+        }
+        if self.supports_credentials {
+            set_header(response, "Access-Control-Allow-Credentials", "true");
+        }
+    }
+
+    /// Answer a preflight request, `None` if it is not allowed.
+    fn preflight_response(&self, request: &Request, origin: &str, requested_method: &str) -> Option<Response> {
+        let route_methods = request.url_adapter().allowed_methods();
+        if route_methods.is_empty() {
+            return None;
+        }
+        let methods: Vec<Method> = match self.methods {
+            Some(ref methods) => methods.iter().filter(|method| route_methods.contains(method)).cloned().collect(),
+            None => route_methods,
+        };
+        let requested_method: Method = requested_method.trim().parse().ok()?;
+        if !methods.contains(&requested_method) {
+            return None;
+        }
+        let requested_headers = header_value(request, "Access-Control-Request-Headers").unwrap_or_default();
+        let requested_headers: Vec<&str> = requested_headers.split(',')
+            .map(str::trim)
+            .filter(|header| !header.is_empty())
+            .collect();
+        if let Some(ref allowed_headers) = self.allowed_headers {
+            let all_allowed = requested_headers.iter()
+                .all(|header| allowed_headers.iter().any(|allowed| allowed.eq_ignore_ascii_case(header)));
+            if !all_allowed {
+                return None;
+            }
+        }
+
+        let mut response = Response::new_empty();
+        self.set_origin_headers(&mut response, origin);
+        let methods: Vec<String> = methods.iter().map(|method| method.to_string()).collect();
+        set_header(&mut response, "Access-Control-Allow-Methods", &methods.join(", "));
+        if !requested_headers.is_empty() {
+            set_header(&mut response, "Access-Control-Allow-Headers", &requested_headers.join(", "));
+        }
+        if let Some(max_age) = self.max_age {
+            set_header(&mut response, "Access-Control-Max-Age", &max_age.to_string());
+        }
+        Some(response)
+    }
+}
+
+impl Middleware for Cors {
+    fn handle(&self, request: &mut Request, next: Next) -> PenResult {
+        let origin = match header_value(request, "Origin") {
+            Some(ref origin) if self.is_allowed_origin(origin) => origin.clone(),
+            _ => return next.run(request),
+        };
+        if request.method() == Method::Options {
+            if let Some(requested_method) = header_value(request, "Access-Control-Request-Method") {
+                return match self.preflight_response(request, &origin, &requested_method) {
+                    Some(response) => Ok(response),
+                    None => next.run(request),
+                };
+            }
+        }
+        let mut response = match next.run(request) {
+            Ok(response) => response,
+            // Browsers hide responses without CORS headers from the page,
+            // so errors are turned into responses here.
+            Err(e) => {
+                let app = request.app;
+                app.make_error_response(request, e)
+            },
+        };
+        self.set_origin_headers(&mut response, &origin);
+        if !self.exposed_headers.is_empty() {
+            set_header(&mut response, "Access-Control-Expose-Headers", &self.exposed_headers.join(", "));
+        }
+        Ok(response)
+    }
+}
+
+fn header_value(request: &Request, name: &str) -> Option<String> {
+    request.headers().get_raw(name)
+        .and_then(|values| values.first())
+        .map(|value| String::from_utf8_lossy(value).into_owned())
+}
+
+fn set_header(response: &mut Response, name: &'static str, value: &str) {
+    response.headers.set_raw(name, vec![value.as_bytes().to_vec()]);
+}
+
+fn add_vary(response: &mut Response, value: &str) {
+    let vary = match response.headers.get_raw("Vary").and_then(|values| values.first()) {
+        Some(vary) => format!("{}, {}", String::from_utf8_lossy(vary), value),
+        None => value.to_string(),
+    };
+    set_header(response, "Vary", &vary);
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::method::Method;
+    use regex::Regex;
+
+    use app::Pen;
+    use testing::{Client, TestRequest, TestResponse};
+    use types::{PenResult, UserError};
+    use wrappers::Request;
+    use super::Cors;
+
+    fn view(_: &mut Request) -> &'static str {
+        "view"
+    }
+
+    fn failing_view(_: &mut Request) -> PenResult {
+        Err(UserError::new("failed").into())
+    }
+
+    fn app_with(cors: Cors) -> Pen {
+        let mut app = Pen::new("/web/pen");
+        app.route("/api", &[Method::Get, Method::Post], "api", view);
+        app.get("/fail", "fail", failing_view);
+        app.add_middleware(cors);
+        app
+    }
+
+    fn get(app: &Pen, path: &str, origin: &str) -> TestResponse {
+        Client::new(app).open(TestRequest::new(Method::Get, path).header("Origin", origin))
+    }
+
+    fn preflight(app: &Pen, method: &str, headers: Option<&str>) -> TestResponse {
+        let mut request = TestRequest::new(Method::Options, "/api")
+            .header("Origin", "https://example.com")
+            .header("Access-Control-Request-Method", method);
+        if let Some(headers) = headers {
+            request = request.header("Access-Control-Request-Headers", headers);
+        }
+        Client::new(app).open(request)
+    }
+
+    #[test]
+    fn test_preflight() {
+        let app = app_with(Cors::new().allow_headers(&["Content-Type"]).max_age(60));
+        let response = preflight(&app, "POST", Some("content-type"));
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.header("Access-Control-Allow-Origin").unwrap(), "*");
+        let methods = response.header("Access-Control-Allow-Methods").unwrap();
+        assert!(methods.contains("GET") && methods.contains("POST"), "{}", methods);
+        assert_eq!(response.header("Access-Control-Allow-Headers").unwrap(), "content-type");
+        assert_eq!(response.header("Access-Control-Max-Age").unwrap(), "60");
+
+        assert!(preflight(&app, "DELETE", None).header("Access-Control-Allow-Origin").is_none());
+        assert!(preflight(&app, "POST", Some("X-Secret")).header("Access-Control-Allow-Origin").is_none());
+    }
+
+    #[test]
+    fn test_preflight_allowed_methods() {
+        let app = app_with(Cors::new().allow_methods(&[Method::Post, Method::Delete]));
+        let response = preflight(&app, "POST", None);
+        assert_eq!(response.header("Access-Control-Allow-Methods").unwrap(), "POST");
+        assert!(preflight(&app, "GET", None).header("Access-Control-Allow-Origin").is_none());
+    }
+
+    #[test]
+    fn test_origin_wildcard() {
+        let app = app_with(Cors::new().allow_origin("https://*.example.com"));
+        let response = get(&app, "/api", "https://api.example.com");
+        assert_eq!(response.header("Access-Control-Allow-Origin").unwrap(), "https://api.example.com");
+        assert_eq!(response.header("Vary").unwrap(), "Origin");
+        for origin in &["https://example.org", "http://api.example.com", "https://evil.com/.example.com"] {
+            assert!(get(&app, "/api", origin).header("Access-Control-Allow-Origin").is_none(), "{}", origin);
+        }
+    }
+
+    #[test]
+    fn test_origin_regex() {
+        let regex = Regex::new(r"^https://(www\.)?example\.(com|org)$").unwrap();
+        let app = app_with(Cors::new().allow_origin("https://localhost:8000").allow_origin_regex(regex));
+        for origin in &["https://example.org", "https://www.example.com", "https://localhost:8000"] {
+            assert_eq!(get(&app, "/api", origin).header("Access-Control-Allow-Origin").unwrap(), *origin);
+        }
+        assert!(get(&app, "/api", "https://example.net").header("Access-Control-Allow-Origin").is_none());
+    }
+
+    #[test]
+    fn test_credentials() {
+        let app = app_with(Cors::new().expose_headers(&["X-Total"]));
+        let response = get(&app, "/api", "https://example.com");
+        assert_eq!(response.header("Access-Control-Allow-Origin").unwrap(), "*");
+        assert!(response.header("Access-Control-Allow-Credentials").is_none());
+        assert!(response.header("Vary").is_none());
+        assert_eq!(response.header("Access-Control-Expose-Headers").unwrap(), "X-Total");
+
+        // Browsers reject `*` for requests with credentials.
+        let app = app_with(Cors::new().allow_origin("*").supports_credentials(true));
+        let response = get(&app, "/api", "https://example.com");
+        assert_eq!(response.header("Access-Control-Allow-Origin").unwrap(), "https://example.com");
+        assert_eq!(response.header("Access-Control-Allow-Credentials").unwrap(), "true");
+        assert_eq!(response.header("Vary").unwrap(), "Origin");
+    }
+
+    #[test]
+    fn test_error_responses() {
+        let app = app_with(Cors::new().allow_origin("https://example.com"));
+        let response = get(&app, "/missing", "https://example.com");
+        assert_eq!(response.status_code, 404);
+        assert_eq!(response.header("Access-Control-Allow-Origin").unwrap(), "https://example.com");
+        let response = get(&app, "/fail", "https://example.com");
+        assert_eq!(response.status_code, 500);
+        assert_eq!(response.header("Access-Control-Allow-Origin").unwrap(), "https://example.com");
+    }
+}
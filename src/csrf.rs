@@ -0,0 +1,355 @@
+//! This module implements protection against cross-site request forgery
+//! as a middleware.  Every client gets a random token kept in a signed
+//! cookie, requests with unsafe methods have to send the same token in a
+//! form field or the `X-CSRF-Token` header.  Requests failing the check
+//! get `HTTPError::Forbidden`, the reason is kept in the request and can
+//! be read with `csrf_error`.
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+
+use hyper::method::Method;
+use typemap::Key;
+
+use cookies::{CookieBuilder, SameSite};
+use http_errors::HTTPError;
+use middleware::{Middleware, Next};
+use sessions::app_signer;
+use signing::{constant_time_eq, generate_token};
+use types::{PenResult, UserError};
+use wrappers::Request;
+
+const CSRF_SALT: &str = "pen.csrf";
+
+/// The name of the form field checked by default.
+pub const DEFAULT_CSRF_FIELD_NAME: &str = "csrf_token";
+
+/// The header checked for the token.
+pub const CSRF_HEADER_NAME: &str = "X-CSRF-Token";
+
+/// The number of bytes of a multipart body searched for the token.
+const MULTIPART_TOKEN_SEARCH_LENGTH: u64 = 64 * 1024;
+
+struct CsrfToken;
+
+impl Key for CsrfToken {
+    type Value = String;
+}
+
+/// The reason a request failed the CSRF check.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CsrfError {
+    /// The token cookie is missing or its signature is invalid.
+    InvalidCookie,
+    /// The request sent no token.
+    MissingToken,
+    /// The sent token does not match the cookie.
+    InvalidToken,
+}
+
+impl fmt::Display for CsrfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl Error for CsrfError {
+    fn description(&self) -> &str {
+        match *self {
+            CsrfError::InvalidCookie => "The CSRF cookie is missing or invalid.",
+            CsrfError::MissingToken => "The CSRF token is missing.",
+            CsrfError::InvalidToken => "The CSRF token is invalid.",
+        }
+    }
+}
+
+impl Key for CsrfError {
+    type Value = CsrfError;
+}
+
+/// The CSRF token of the client, if the `Csrf` middleware handles the
+/// request.  Put it into forms as hidden field, it is also available in
+/// templates as `csrf_token`.
+pub fn csrf_token(request: &Request) -> Option<String> {
+    request.extensions_data.get::<CsrfToken>().cloned()
+}
+
+/// Why the `Csrf` middleware rejected the request, if it did.
+pub fn csrf_error(request: &Request) -> Option<CsrfError> {
+    request.extensions_data.get::<CsrfError>().cloned()
+}
+
+fn forbidden(request: &mut Request, reason: CsrfError) -> PenResult {
+    request.extensions_data.insert::<CsrfError>(reason);
+    Err(HTTPError::Forbidden.into())
+}
+
+/// The CSRF middleware.  It needs the `SECRET_KEY` to sign the token
+/// cookie.
+///
+/// Multipart bodies are not loaded as a whole, so views can still read
+/// them with `Request::multipart`.  The token has to be sent in the header
+/// or in a field within the first 64 KiB of a multipart form, so put the
+/// hidden field before large file inputs.
+///
+/// ```ignore
+/// app.add_middleware(Csrf::new().exempt("api.webhook"));
+/// ```
+pub struct Csrf {
+    cookie_name: String,
+    field_name: String,
+    exempt_endpoints: HashSet<String>,
+}
+
+impl Default for Csrf {
+    fn default() -> Csrf { Csrf::new() }
+}
+
+impl Csrf {
+    pub fn new() -> Csrf {
+        Csrf {
+            cookie_name: String::from("csrf_token"),
+            field_name: String::from(DEFAULT_CSRF_FIELD_NAME),
+            exempt_endpoints: HashSet::new(),
+        }
+    }
+
+    pub fn cookie_name(mut self, cookie_name: &str) -> Csrf {
+        self.cookie_name = cookie_name.to_string();
+        self
+    }
+
+    /// The form field the token is read from.
+    pub fn field_name(mut self, field_name: &str) -> Csrf {
+        self.field_name = field_name.to_string();
+        self
+    }
+
+    /// Do not check requests to the endpoint.
+    pub fn exempt(mut self, endpoint: &str) -> Csrf {
+        self.exempt_endpoints.insert(endpoint.to_string());
+        self
+    }
+
+    fn is_exempt(&self, request: &Request) -> bool {
+        match request.endpoint() {
+            Some(endpoint) => self.exempt_endpoints.contains(&endpoint),
+            None => false,
+        }
+    }
+
+    /// The token sent by the client in the header or the form.  Only the
+    /// start of a multipart body is read, it is read again by the view.
+    fn submitted_token(&self, request: &Request) -> Option<String> {
+        let header = request.headers().get_raw(CSRF_HEADER_NAME)
+            .and_then(|values| values.first())
+            .map(|value| String::from_utf8_lossy(value).into_owned());
+        match header {
+            Some(token) => Some(token),
+            None if request.is_multipart() => request.peek_multipart_field(&self.field_name, MULTIPART_TOKEN_SEARCH_LENGTH),
+            None => request.form().get(&self.field_name).cloned(),
+        }
+    }
+}
+
+fn is_safe_method(method: &Method) -> bool {
+    matches!(*method, Method::Get | Method::Head | Method::Options | Method::Trace)
+}
+
+impl Middleware for Csrf {
+    fn handle(&self, request: &mut Request, next: Next) -> PenResult {
+        let signer = match app_signer(request.app, CSRF_SALT) {
+            Some(signer) => signer,
+            None => return Err(UserError::new("CSRF protection requires a SECRET_KEY").into()),
+        };
+        let cookie_token = request.cookie(&self.cookie_name)
+            .and_then(|value| signer.unsign(&value, None));
+
+        if !is_safe_method(&request.method()) && !self.is_exempt(request) {
+            let cookie_token = match cookie_token {
+                Some(ref token) => token,
+                None => return forbidden(request, CsrfError::InvalidCookie),
+            };
+            match self.submitted_token(request) {
+                Some(ref token) if constant_time_eq(token, cookie_token) => (),
+                Some(_) => return forbidden(request, CsrfError::InvalidToken),
+                None => return forbidden(request, CsrfError::MissingToken),
+            }
+        }
+
+        let (token, is_new) = match cookie_token {
+            Some(token) => (token, false),
+            None => (generate_token(), true),
+        };
+        request.extensions_data.insert::<CsrfToken>(token.clone());
+        let mut response = next.run(request)?;
+        if is_new {
+            let app = request.app;
+            response.add_cookie(CookieBuilder::new(&self.cookie_name, &signer.sign(&token))
+                .path("/")
+                .secure(app.config.get_boolean("SESSION_COOKIE_SECURE", false))
+                .http_only(true)
+                .same_site(SameSite::Lax));
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::method::Method;
+
+    use app::Pen;
+    use middleware::Next;
+    use testing::{Client, TestRequest};
+    use types::PenResult;
+    use wrappers::{Request, Response};
+    use super::{Csrf, CsrfError, csrf_error, csrf_token};
+
+    const MULTIPART: &str = "multipart/form-data; boundary=XyZ";
+
+    fn app() -> Pen {
+        let mut app = Pen::new("/web/pen");
+        app.config.set("SECRET_KEY", "secret");
+        // Report the reason of rejected requests.
+        app.add_middleware(|request: &mut Request, next: Next| -> PenResult {
+            next.run(request).or_else(|e| match csrf_error(request) {
+                Some(reason) => Ok(Response::from(format!("{:?}", reason))),
+                None => Err(e),
+            })
+        });
+        app.add_middleware(Csrf::new().exempt("hook"));
+        app.route("/", &[Method::Get, Method::Post], "index", |request: &mut Request| {
+            csrf_token(request).unwrap_or_default()
+        });
+        app.post("/hook", "hook", |_: &mut Request| "hooked");
+        app.post("/upload", "upload", |request: &mut Request| -> PenResult {
+            let mut multipart = request.multipart()?;
+            let mut names = Vec::new();
+            while let Some(part) = multipart.next_part()? {
+                names.push(part.name().unwrap_or("").to_string());
+            }
+            Ok(Response::from(names.join(",")))
+        });
+        app
+    }
+
+    fn post(client: &mut Client, path: &str, fields: &[(&str, &str)]) -> String {
+        client.open(TestRequest::new(Method::Post, path).form(fields)).text()
+    }
+
+    fn multipart(parts: &[(&str, Option<&str>, String)]) -> String {
+        let mut body = String::new();
+        for &(name, filename, ref value) in parts {
+            let filename = filename.map(|filename| format!("; filename=\"{}\"", filename)).unwrap_or_default();
+            body.push_str(&format!("--XyZ\r\nContent-Disposition: form-data; name=\"{}\"{}\r\n\r\n{}\r\n",
+                                   name, filename, value));
+        }
+        body + "--XyZ--\r\n"
+    }
+
+    #[test]
+    fn test_token_cookie() {
+        let app = app();
+        let mut client = Client::new(&app);
+        let token = client.get("/").text();
+        assert!(!token.is_empty());
+        assert!(client.cookies.contains_key("csrf_token"));
+        assert_eq!(client.get("/").text(), token);
+        // Another client gets another token.
+        assert!(Client::new(&app).get("/").text() != token);
+    }
+
+    #[test]
+    fn test_safe_methods() {
+        let app = app();
+        let mut client = Client::new(&app);
+        assert_eq!(client.get("/").status_code, 200);
+        assert_eq!(client.head("/").status_code, 200);
+        assert_eq!(client.open(TestRequest::new(Method::Options, "/")).status_code, 200);
+    }
+
+    #[test]
+    fn test_invalid_cookie() {
+        let app = app();
+        let mut client = Client::new(&app);
+        assert_eq!(post(&mut client, "/", &[]), format!("{:?}", CsrfError::InvalidCookie));
+        let token = client.get("/").text();
+        let cookie = client.cookies["csrf_token"].clone();
+        client.cookies.insert("csrf_token".to_string(), format!("{}x", cookie));
+        assert_eq!(post(&mut client, "/", &[("csrf_token", &token)]), format!("{:?}", CsrfError::InvalidCookie));
+        // A cookie signed with another key is rejected as well.
+        let mut other_app = Pen::new("/web/pen");
+        other_app.config.set("SECRET_KEY", "other");
+        other_app.add_middleware(Csrf::new());
+        other_app.route("/", &[Method::Get, Method::Post], "index", |_: &mut Request| "index");
+        let mut other_client = Client::new(&other_app);
+        other_client.cookies.insert("csrf_token".to_string(), cookie);
+        assert_eq!(other_client.post("/", "").status_code, 403);
+    }
+
+    #[test]
+    fn test_submitted_token() {
+        let app = app();
+        let mut client = Client::new(&app);
+        let token = client.get("/").text();
+        assert_eq!(post(&mut client, "/", &[("other", "value")]), format!("{:?}", CsrfError::MissingToken));
+        assert_eq!(post(&mut client, "/", &[("csrf_token", "wrong")]), format!("{:?}", CsrfError::InvalidToken));
+        assert_eq!(post(&mut client, "/", &[("csrf_token", &token)]), token);
+        let request = TestRequest::new(Method::Post, "/").header("X-CSRF-Token", &token);
+        assert_eq!(client.open(request).text(), token);
+        let request = TestRequest::new(Method::Post, "/").header("X-CSRF-Token", "wrong").form(&[("csrf_token", &token)]);
+        assert_eq!(client.open(request).text(), format!("{:?}", CsrfError::InvalidToken));
+    }
+
+    #[test]
+    fn test_forbidden_without_handler() {
+        let mut app = Pen::new("/web/pen");
+        app.config.set("SECRET_KEY", "secret");
+        app.add_middleware(Csrf::new());
+        app.post("/", "index", |_: &mut Request| "index");
+        assert_eq!(Client::new(&app).post("/", "").status_code, 403);
+    }
+
+    #[test]
+    fn test_exempt() {
+        let app = app();
+        let mut client = Client::new(&app);
+        assert_eq!(post(&mut client, "/hook", &[]), "hooked");
+        // The exempt request got a token cookie, but no token was sent.
+        assert!(client.cookies.contains_key("csrf_token"));
+        assert_eq!(post(&mut client, "/", &[]), format!("{:?}", CsrfError::MissingToken));
+    }
+
+    #[test]
+    fn test_multipart() {
+        let app = app();
+        let mut client = Client::new(&app);
+        let token = client.get("/").text();
+        let upload = |client: &mut Client, body: String| {
+            client.open(TestRequest::new(Method::Post, "/upload").header("Content-Type", MULTIPART).body(body)).text()
+        };
+        let body = multipart(&[("csrf_token", None, token.clone()), ("file", Some("a.txt"), "x".repeat(20000))]);
+        assert_eq!(upload(&mut client, body), "csrf_token,file");
+        // The token may follow other parts.
+        let body = multipart(&[("file", Some("a.txt"), "x".repeat(20000)), ("csrf_token", None, token.clone())]);
+        assert_eq!(upload(&mut client, body), "file,csrf_token");
+        // A file part with the name of the field is not a token.
+        let body = multipart(&[("csrf_token", Some("a.txt"), token.clone())]);
+        assert_eq!(upload(&mut client, body), format!("{:?}", CsrfError::MissingToken));
+        // Only the start of the body is searched.
+        let body = multipart(&[("file", Some("a.txt"), "x".repeat(100 * 1024)), ("csrf_token", None, token.clone())]);
+        assert_eq!(upload(&mut client, body), format!("{:?}", CsrfError::MissingToken));
+        let body = multipart(&[("csrf_token", None, "wrong".to_string())]);
+        assert_eq!(upload(&mut client, body), format!("{:?}", CsrfError::InvalidToken));
+    }
+
+    #[test]
+    fn test_requires_secret_key() {
+        let mut app = Pen::new("/web/pen");
+        app.add_middleware(Csrf::new());
+        app.get("/", "index", |_: &mut Request| "index");
+        assert_eq!(Client::new(&app).get("/").status_code, 500);
+    }
+}
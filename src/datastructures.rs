@@ -1,8 +1,14 @@
+use std::any::Any;
+use std::fmt;
 use std::iter;
+use std::ops::Index;
+use std::str::FromStr;
 use std::borrow::Borrow;
 use std::collections::HashMap;
 use std::collections::hash_map;
 
+use http_errors::{HTTPError, BadRequest, NotFound};
+
 type MultiDictListIter<'a, T> = hash_map::Iter<'a, String, Vec<T>>;
 type MultiDictListValues<'a, T> = hash_map::Values<'a, String, Vec<T>>;
 
@@ -89,3 +95,106 @@ impl<T> MultiDict<T> {
         self.map.values()
     }
 }
+
+struct ViewArg {
+    raw: String,
+    value: Box<Any + Send + Sync>,
+}
+
+pub struct ViewArgsIter<'a> {
+    inner: hash_map::Iter<'a, String, ViewArg>,
+}
+
+impl<'a> iter::Iterator for ViewArgsIter<'a> {
+    type Item = (&'a String, &'a String);
+    #[inline] fn next(&mut self) -> Option<(&'a String, &'a String)> { self.inner.next().map(|(k, v)| (k, &v.raw)) }
+    #[inline] fn size_hint(&self) -> (usize, Option<usize>) { self.inner.size_hint() }
+}
+
+/// The arguments of a view.  Each argument keeps the raw url segment it
+/// was matched from as well as the value produced by the rule converter,
+/// so `<id:int>` is available as an `i64` without parsing it again.
+pub struct ViewArgs {
+    map: HashMap<String, ViewArg>,
+}
+
+impl ViewArgs {
+    pub fn new() -> ViewArgs {
+        ViewArgs {
+            map: HashMap::new(),
+        }
+    }
+
+    /// Insert a value, its raw form is the string representation.
+    pub fn insert<T: Any + Send + Sync + ToString>(&mut self, name: &str, value: T) {
+        let raw = value.to_string();
+        self.insert_converted(name, raw, Box::new(value));
+    }
+
+    /// Insert a raw value together with its already converted value.
+    pub fn insert_converted(&mut self, name: &str, raw: String, value: Box<Any + Send + Sync>) {
+        self.map.insert(name.to_owned(), ViewArg { raw: raw, value: value });
+    }
+
+    /// Get the converted value of an argument.  Returns `NotFound` if the
+    /// argument does not exist and `BadRequest` if it has a different type.
+    pub fn get<T: Any>(&self, name: &str) -> Result<&T, HTTPError> {
+        match self.map.get(name) {
+            Some(arg) => arg.value.downcast_ref::<T>().ok_or(BadRequest),
+            None => Err(NotFound),
+        }
+    }
+
+    /// Parse the raw value of an argument.  Returns `NotFound` if the
+    /// argument does not exist and `BadRequest` if it can not be parsed.
+    pub fn parse<T: FromStr>(&self, name: &str) -> Result<T, HTTPError> {
+        match self.map.get(name) {
+            Some(arg) => arg.raw.parse().map_err(|_| BadRequest),
+            None => Err(NotFound),
+        }
+    }
+
+    /// Get the raw value of an argument.
+    pub fn get_str(&self, name: &str) -> Option<&str> {
+        self.map.get(name).map(|arg| arg.raw.as_str())
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.map.contains_key(name)
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.map.remove(name).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Iterate over the names and raw values.
+    pub fn iter(&self) -> ViewArgsIter {
+        ViewArgsIter { inner: self.map.iter() }
+    }
+}
+
+impl Default for ViewArgs {
+    fn default() -> ViewArgs { ViewArgs::new() }
+}
+
+impl<'a> Index<&'a str> for ViewArgs {
+    type Output = String;
+
+    fn index(&self, name: &str) -> &String {
+        &self.map[name].raw
+    }
+}
+
+impl fmt::Debug for ViewArgs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
@@ -1,13 +1,114 @@
 //! This module implements the form parsing. It supports url-encoded forms
 //! as well as multipart uploads.
 
-use std::io::Read;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
 
 use url::form_urlencoded;
+use url::percent_encoding::percent_decode;
 use hyper::header::Headers;
 use datastructures::MultiDict;
-use formdata::{read_formdata, FilePart};
-use hyper::mime::{Mime, TopLevel, SubLevel};
+use formdata::{self, read_formdata, FilePart};
+use hyper::mime::{Mime, TopLevel, SubLevel, Attr, Value};
+use http_errors::HTTPError;
+
+/// The reasons a form body could not be parsed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FormError {
+    /// The body is not valid UTF-8 or its encoding is broken.
+    BadEncoding,
+    /// The multipart boundary is missing or invalid.
+    BadBoundary,
+    /// The body ended before the form was complete.
+    Truncated,
+    /// The body exceeds the maximum content length.
+    SizeExceeded,
+    /// The body uses a charset other than UTF-8.
+    UnsupportedCharset(String),
+    /// The body is malformed in another way.
+    Malformed(String),
+    /// A multipart body was expected but the request has another type.
+    NotMultipart,
+    /// Writing an uploaded file failed.
+    Storage(String),
+    /// The body was already read, like by `Request::form` before
+    /// `Request::multipart`.
+    BodyConsumed,
+}
+
+impl FormError {
+    /// The HTTP error used if no form error handler is registered.
+    pub fn to_http_error(&self) -> HTTPError {
+        match *self {
+            FormError::SizeExceeded => HTTPError::RequestEntityTooLarge,
+            FormError::UnsupportedCharset(_) | FormError::NotMultipart => HTTPError::UnsupportedMediaType,
+            FormError::Storage(_) | FormError::BodyConsumed => HTTPError::InternalServerError,
+            _ => HTTPError::BadRequest,
+        }
+    }
+}
+
+impl fmt::Display for FormError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FormError::UnsupportedCharset(ref charset) => write!(f, "unsupported form charset {}", charset),
+            FormError::Malformed(ref desc) => write!(f, "malformed form data: {}", desc),
+            FormError::Storage(ref desc) => write!(f, "could not store the upload: {}", desc),
+            _ => f.write_str(self.description()),
+        }
+    }
+}
+
+impl Error for FormError {
+    fn description(&self) -> &str {
+        match *self {
+            FormError::BadEncoding => "the form data is not properly encoded",
+            FormError::BadBoundary => "the multipart boundary is missing or invalid",
+            FormError::Truncated => "the form data is truncated",
+            FormError::SizeExceeded => "the form data exceeds the maximum content length",
+            FormError::UnsupportedCharset(_) => "unsupported form charset",
+            FormError::Malformed(_) => "malformed form data",
+            FormError::NotMultipart => "the request body is not multipart form data",
+            FormError::Storage(_) => "could not store the upload",
+            FormError::BodyConsumed => "the request body was already read",
+        }
+    }
+}
+
+/// Only UTF-8 and its subset ASCII are supported.
+pub(crate) fn check_charset(mimetype: &Mime) -> Result<(), FormError> {
+    match mimetype.get_param(Attr::Charset) {
+        None | Some(Value::Utf8) => Ok(()),
+        Some(Value::Ext(charset)) if charset.eq_ignore_ascii_case("us-ascii") => Ok(()),
+        Some(charset) => Err(FormError::UnsupportedCharset(charset.to_string())),
+    }
+}
+
+/// A boundary has 1 to 70 characters, see RFC 2046.
+pub(crate) fn check_boundary(mimetype: &Mime) -> Result<(), FormError> {
+    match mimetype.get_param(Attr::Boundary) {
+        Some(boundary) if !boundary.is_empty() && boundary.len() <= 70 => Ok(()),
+        _ => Err(FormError::BadBoundary),
+    }
+}
+
+pub(crate) fn io_error(e: &io::Error) -> FormError {
+    match e.kind() {
+        io::ErrorKind::InvalidData => FormError::BadEncoding,
+        _ => FormError::Truncated,
+    }
+}
+
+fn multipart_error(e: formdata::Error) -> FormError {
+    match e {
+        formdata::Error::BoundaryNotSpecified => FormError::BadBoundary,
+        formdata::Error::Eof | formdata::Error::PartialHeaders => FormError::Truncated,
+        formdata::Error::Io(ref e) => io_error(e),
+        formdata::Error::Utf8(_) | formdata::Error::Decoding(_) => FormError::BadEncoding,
+        e => FormError::Malformed(e.to_string()),
+    }
+}
 
 /// This type implements parsing of form data for Pen. It can parse
 /// multipart and url encoded form data.
@@ -18,38 +119,40 @@ impl FormDataParser {
         FormDataParser
     }
 
-    pub fn parse<B: Read>(&self, body: &mut B, headers: &Headers, mimetype: &Mime) -> (MultiDict<String>, MultiDict<FilePart>) {
+    pub fn parse<B: Read>(&self, body: &mut B, headers: &Headers, mimetype: &Mime)
+        -> Result<(MultiDict<String>, MultiDict<FilePart>), FormError>
+    {
         match *mimetype {
             Mime(TopLevel::Application, SubLevel::WwwFormUrlEncoded, _) => {
+                check_charset(mimetype)?;
                 let mut body_vec: Vec<u8> = Vec::new();
-                match body.read_to_end(&mut body_vec) {
-                    Ok(_) => {
-                        let mut form = MultiDict::new();
-                        for (k, v) in form_urlencoded::parse(&body_vec).into_owned() {
-                            form.add(k, v);
-                        }
-                        (form, MultiDict::new())
-                    },
-                    Err(_) => (MultiDict::new(), MultiDict::new())
+                body.read_to_end(&mut body_vec).map_err(|e| io_error(&e))?;
+                let well_encoded = body_vec.split(|b| *b == b'&' || *b == b'=')
+                    .all(|part| percent_decode(part).decode_utf8().is_ok());
+                if !well_encoded {
+                    return Err(FormError::BadEncoding);
+                }
+                let mut form = MultiDict::new();
+                for (k, v) in form_urlencoded::parse(&body_vec).into_owned() {
+                    form.add(k, v);
                 }
+                Ok((form, MultiDict::new()))
             },
             Mime(TopLevel::Multipart, SubLevel::FormData, _) => {
-                match read_formdata(body, headers) {
-                    Ok(form_data) => {
-                        let mut form = MultiDict::new();
-                        let mut files = MultiDict::new();
-                        for (name, value) in form_data.fields {
-                            form.add(name, value);
-                        }
-                        for (name, file) in form_data.files {
-                            files.add(name, file);
-                        }
-                        (form, files)
-                    },
-                    Err(_) => (MultiDict::new(), MultiDict::new())
+                check_charset(mimetype)?;
+                check_boundary(mimetype)?;
+                let form_data = read_formdata(body, headers).map_err(multipart_error)?;
+                let mut form = MultiDict::new();
+                let mut files = MultiDict::new();
+                for (name, value) in form_data.fields {
+                    form.add(name, value);
+                }
+                for (name, file) in form_data.files {
+                    files.add(name, file);
                 }
+                Ok((form, files))
             },
-            _ => (MultiDict::new(), MultiDict::new())
+            _ => Ok((MultiDict::new(), MultiDict::new()))
         }
     }
 }
@@ -10,7 +10,8 @@ use hyper::header::ContentRangeSpec::{Bytes};
 
 use mime_guess::{guess_mime_type, Mime};
 
-use wrappers::Response;
+use wrappers::{Request, Response};
+use sessions::open_flashes;
 use types::{
     PenHTTPError,
     PenResult,
@@ -58,6 +59,36 @@ pub fn redirect(location: &str, code: u16) -> PenResult {
     Ok(response)
 }
 
+/// Flash a message to the next request, for example to show it after a
+/// redirect.  The messages are kept in a signed cookie until they are
+/// read with `get_flashed_messages`.
+pub fn flash(request: &mut Request, category: &str, message: &str) {
+    let (ref mut flashes, ref mut modified) = *load_flashes(request);
+    flashes.push((category.to_owned(), message.to_owned()));
+    *modified = true;
+}
+
+/// Get and remove the flashed messages as `(category, message)` pairs.
+/// If categories are given only messages of these categories are
+/// returned, the others are kept.
+pub fn get_flashed_messages(request: &mut Request, categories: &[&str]) -> Vec<(String, String)> {
+    let (ref mut flashes, ref mut modified) = *load_flashes(request);
+    let (messages, kept) = flashes.drain(..)
+        .partition(|(category, _)| categories.is_empty() || categories.contains(&category.as_str()));
+    *flashes = kept;
+    if !messages.is_empty() {
+        *modified = true;
+    }
+    messages
+}
+
+fn load_flashes<'r>(request: &'r mut Request) -> &'r mut (Vec<(String, String)>, bool) {
+    if request.flashes.is_none() {
+        request.flashes = Some((open_flashes(request), false));
+    }
+    request.flashes.as_mut().unwrap()
+}
+
 pub fn escape(s: &str) -> String {
     s.replace("&", "&amp;").replace("<", "&lt;")
      .replace(">", "&gt;").replace("\"", "&quot;")
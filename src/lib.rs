@@ -10,18 +10,38 @@ extern crate typemap;
 extern crate mime;
 extern crate mime_guess;
 extern crate lazycell;
+extern crate hmac;
+extern crate sha2;
+extern crate base64;
+extern crate rand;
+extern crate time;
+#[cfg(any(feature = "json", feature = "templates"))] extern crate serde;
+#[cfg(feature = "json")] extern crate serde_json;
+#[cfg(feature = "toml")] extern crate toml;
+#[cfg(feature = "templates")] extern crate handlebars;
 
 /* public api */
 pub use app::Pen;
+pub use module::Module;
+pub use config::Config;
+pub use formparser::FormError;
+pub use multipart::{Multipart, Part};
+pub use middleware::{Middleware, Next};
+pub use cors::Cors;
+pub use csrf::{Csrf, CsrfError, csrf_token, csrf_error};
+pub use cookies::{CookieBuilder, SameSite};
+pub use sessions::{Session, SessionStore, MemorySessionStore, FileSystemSessionStore};
 pub use types::{
     PenError,
         PenHTTPError,
         PenUserError,
+        PenFormError,
     UserError,
     PenResult,
     ViewArgs,
     ViewFunc,
     UserErrorHandler,
+    FormErrorHandler,
     HTTPErrorHandler,
     BeforeRequestFunc,
     AfterRequestFunc,
@@ -30,6 +50,7 @@ pub use types::{
 pub use wrappers::{
     Request,
     Response,
+    Responder,
 };
 pub use http_errors::{
     HTTPError
@@ -40,9 +61,12 @@ pub use helpers::{
     abort,
     redirect,
     escape,
+    flash,
+    get_flashed_messages,
     send_file,
     send_from_directory,
 };
+pub use templating::render_template;
 pub use hyper::header::{Cookie, SetCookie, Headers, ContentLength, ContentType};
 
 #[macro_use] mod utils;
@@ -52,7 +76,19 @@ pub mod wrappers;
 pub mod routing;
 pub mod helpers;
 pub mod method;
+pub mod config;
+pub mod templating;
+pub mod signing;
+pub mod sessions;
+pub mod cookies;
+pub mod middleware;
+pub mod cors;
+pub mod csrf;
+pub mod multipart;
+#[cfg(feature = "openapi")] pub mod openapi;
+pub mod testing;
 mod app;
+mod module;
 mod types;
 mod serving;
 mod httputils;
@@ -0,0 +1,120 @@
+//! This module implements middlewares, which wrap the dispatching of a
+//! request and can act before and after the view function in one place.
+
+use app::Pen;
+use types::PenResult;
+use wrappers::Request;
+
+/// A middleware wraps the handling of a request.  It gets the request and
+/// the rest of the middleware stack, it may return a response itself or
+/// call `next.run(request)` and inspect or change its result.
+///
+/// The innermost handler runs the `before_request` functions and the view
+/// function.  Errors reach the middlewares as `Err`, so they can be
+/// translated into responses.  Errors left over are passed to the error
+/// handlers after the stack returns, then the `after_request` functions
+/// run on the final response.
+pub trait Middleware: Send + Sync {
+    fn handle(&self, request: &mut Request, next: Next) -> PenResult;
+}
+
+impl<F> Middleware for F where F: Fn(&mut Request, Next) -> PenResult + Send + Sync {
+    fn handle(&self, request: &mut Request, next: Next) -> PenResult {
+        self(request, next)
+    }
+}
+
+/// The rest of the middleware stack of a request.
+pub struct Next<'m> {
+    app: &'m Pen,
+    middlewares: &'m [&'m Middleware],
+}
+
+impl<'m> Next<'m> {
+    pub(crate) fn new(app: &'m Pen, middlewares: &'m [&'m Middleware]) -> Next<'m> {
+        Next {
+            app: app,
+            middlewares: middlewares,
+        }
+    }
+
+    /// Run the remaining middlewares and dispatch the request.
+    pub fn run(self, request: &mut Request) -> PenResult {
+        match self.middlewares.split_first() {
+            Some((middleware, rest)) => middleware.handle(request, Next::new(self.app, rest)),
+            None => self.app.full_dispatch_request(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use app::Pen;
+    use http_errors::HTTPError;
+    use testing::Client;
+    use types::{PenError, PenResult, UserError};
+    use wrappers::{Request, Response};
+    use super::Next;
+
+    fn failing_view(_: &mut Request) -> PenResult {
+        Err(UserError::new("failed").into())
+    }
+
+    #[test]
+    fn test_errors_reach_middlewares() {
+        let mut app = Pen::new("/web/pen");
+        app.get("/", "index", failing_view);
+        app.add_middleware(|request: &mut Request, next: Next| -> PenResult {
+            match next.run(request) {
+                Err(PenError::PenHTTPError(HTTPError::NotFound)) => Ok(Response::from("translated 404")),
+                Err(PenError::PenUserError(ref e)) => Ok(Response::from(format!("translated {}", e.desc))),
+                result => result,
+            }
+        });
+        let mut client = Client::new(&app);
+        assert_eq!(client.get("/missing").text(), "translated 404");
+        assert_eq!(client.get("/").text(), "translated failed");
+    }
+
+    #[test]
+    fn test_errors_handled_once() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut app = Pen::new("/web/pen");
+        app.get("/", "index", failing_view);
+        let handler_calls = calls.clone();
+        app.register_user_error_handler("failed", move |_| {
+            handler_calls.fetch_add(1, Ordering::SeqCst);
+            Err(UserError::new("failed again").into())
+        });
+        app.add_middleware(|request: &mut Request, next: Next| next.run(request));
+        assert_eq!(Client::new(&app).get("/").status_code, 500);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_after_request_on_middleware_errors() {
+        let mut app = Pen::new("/web/pen");
+        app.config.set("SECRET_KEY", "secret");
+        app.config.set("MAX_CONTENT_LENGTH", 4u64);
+        app.post("/", "index", |_: &mut Request| "index");
+        app.add_middleware(|request: &mut Request, next: Next| -> PenResult {
+            request.session().set("seen", "1");
+            if request.path() == "/" {
+                return Err(HTTPError::Forbidden.into());
+            }
+            next.run(request)
+        });
+        app.after_request(|_, response| response.headers.set_raw("X-After", vec![b"1".to_vec()]));
+        let mut client = Client::new(&app);
+        let response = client.post("/", "");
+        assert_eq!(response.status_code, 403);
+        assert_eq!(response.header("X-After").as_deref(), Some("1"));
+        assert!(client.cookies.contains_key("session"));
+        let response = client.post("/other", "too large");
+        assert_eq!(response.status_code, 413);
+        assert_eq!(response.header("X-After").as_deref(), Some("1"));
+    }
+}
@@ -0,0 +1,182 @@
+//! This module implements modules, which are sub-applications with their
+//! own routes, hooks, error handlers and static files that are registered
+//! onto a `Pen` application under a url prefix.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+
+use hyper::method::Method;
+
+use app::Pen;
+use types::{
+    PenError,
+    PenResult,
+    UserError,
+    ViewFunc,
+    HTTPErrorHandler,
+    UserErrorHandler,
+    FormErrorHandler,
+    BeforeRequestFunc,
+    AfterRequestFunc,
+    TeardownRequestFunc,
+};
+use wrappers::{
+    Request,
+    Response,
+    Responder,
+};
+use helpers::send_from_directory_range;
+use routing::{IntoMatcher, DeferredMatcher};
+use middleware::Middleware;
+use http_errors::HTTPError;
+use formparser::FormError;
+
+/// A module collects routes, request hooks and error handlers.  All
+/// endpoints of a module are prefixed with the module name and a dot,
+/// so the endpoint `index` of the module `admin` becomes `admin.index`.
+/// Hooks and error handlers of a module only apply to requests that
+/// were routed to one of its endpoints.
+pub struct Module {
+    pub name: String,
+    pub root_path: String,
+    pub static_folder: Option<String>,
+    pub static_url_path: Option<String>,
+    pub template_folder: Option<String>,
+    pub(crate) before_request_funcs: Vec<Box<BeforeRequestFunc>>,
+    pub(crate) after_request_funcs: Vec<Box<AfterRequestFunc>>,
+    pub(crate) teardown_request_funcs: Vec<Box<TeardownRequestFunc>>,
+    pub(crate) http_error_handlers: HashMap<u16, Box<HTTPErrorHandler>>,
+    pub(crate) user_error_handlers: HashMap<String, Box<UserErrorHandler>>,
+    pub(crate) form_error_handler: Option<Box<FormErrorHandler>>,
+    pub(crate) middlewares: Vec<Box<Middleware>>,
+    deferred_routes: Vec<(DeferredMatcher, Vec<Method>, String, Box<ViewFunc>)>,
+}
+
+impl Module {
+    pub fn new(name: &str, root_path: &str) -> Module {
+        if name.contains('.') {
+            panic!("module name should not contain dot");
+        }
+        Module {
+            name: name.to_string(),
+            root_path: root_path.to_string(),
+            static_folder: None,
+            static_url_path: None,
+            template_folder: None,
+            before_request_funcs: vec![],
+            after_request_funcs: vec![],
+            teardown_request_funcs: vec![],
+            http_error_handlers: HashMap::new(),
+            user_error_handlers: HashMap::new(),
+            form_error_handler: None,
+            middlewares: vec![],
+            deferred_routes: vec![],
+        }
+    }
+
+    /// Add a route to the module.  The rule is relative to the url prefix
+    /// the module is registered with, rule strings are compiled with the
+    /// converters of the application when the module is registered.
+    pub fn route<M: IntoMatcher, N: AsRef<[Method]>, F: Fn(&mut Request) -> R + Send + Sync + 'static, R: Responder + 'static>(&mut self, rule: M, methods: N, endpoint: &str, view_func: F) {
+        if endpoint.contains('.') {
+            panic!("module endpoint should not contain dot");
+        }
+        let endpoint = format!("{}.{}", self.name, endpoint);
+        self.deferred_routes.push((rule.into_deferred(), methods.as_ref().to_vec(), endpoint, Box::new(move |request| view_func(request).respond())));
+    }
+
+    pub fn get<M: IntoMatcher, F: Fn(&mut Request) -> R + Send + Sync + 'static, R: Responder + 'static>(&mut self, rule: M, endpoint: &str, view_func: F) {
+        self.route(rule, &[Method::Get], endpoint, view_func);
+    }
+
+    pub fn post<M: IntoMatcher, F: Fn(&mut Request) -> R + Send + Sync + 'static, R: Responder + 'static>(&mut self, rule: M, endpoint: &str, view_func: F) {
+        self.route(rule, &[Method::Post], endpoint, view_func);
+    }
+
+    pub fn delete<M: IntoMatcher, F: Fn(&mut Request) -> R + Send + Sync + 'static, R: Responder + 'static>(&mut self, rule: M, endpoint: &str, view_func: F) {
+        self.route(rule, &[Method::Delete], endpoint, view_func);
+    }
+
+    pub fn patch<M: IntoMatcher, F: Fn(&mut Request) -> R + Send + Sync + 'static, R: Responder + 'static>(&mut self, rule: M, endpoint: &str, view_func: F) {
+        self.route(rule, &[Method::Patch], endpoint, view_func);
+    }
+
+    pub fn put<M: IntoMatcher, F: Fn(&mut Request) -> R + Send + Sync + 'static, R: Responder + 'static>(&mut self, rule: M, endpoint: &str, view_func: F) {
+        self.route(rule, &[Method::Put], endpoint, view_func);
+    }
+
+    pub fn before_request<F: Fn(&mut Request) -> Option<PenResult> + Send + Sync + 'static>(&mut self, f: F) {
+        self.before_request_funcs.push(Box::new(f));
+    }
+
+    pub fn after_request<F: Fn(&Request, &mut Response) + Send + Sync + 'static>(&mut self, f: F) {
+        self.after_request_funcs.push(Box::new(f));
+    }
+
+    pub fn teardown_request<F: Fn(Option<&PenError>) + Send + Sync + 'static>(&mut self, f: F) {
+        self.teardown_request_funcs.push(Box::new(f));
+    }
+
+    /// Add a middleware that wraps the requests routed to this module,
+    /// inside the middlewares of the application.
+    pub fn add_middleware<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.middlewares.push(Box::new(middleware));
+    }
+
+    pub fn register_http_error_handler<F: Fn(HTTPError) -> PenResult + Send + Sync + 'static>(&mut self, status_code: u16, f: F) {
+        self.http_error_handlers.insert(status_code, Box::new(f));
+    }
+
+    pub fn register_user_error_handler<F: Fn(UserError) -> PenResult + Send + Sync + 'static>(&mut self, error_desc: &str, f: F) {
+        self.user_error_handlers.insert(error_desc.to_string(), Box::new(f));
+    }
+
+    pub fn register_form_error_handler<F: Fn(FormError) -> PenResult + Send + Sync + 'static>(&mut self, f: F) {
+        self.form_error_handler = Some(Box::new(f));
+    }
+
+    pub fn httperrorhandler<F: Fn(HTTPError) -> PenResult + Send + Sync + 'static>(&mut self, status_code: u16, f: F) {
+        self.register_http_error_handler(status_code, f);
+    }
+
+    pub fn usererrorhandler<F: Fn(UserError) -> PenResult + Send + Sync + 'static>(&mut self, error_desc: &str, f: F) {
+        self.register_user_error_handler(error_desc, f);
+    }
+
+    /// Add the routes of this module to the application.
+    pub(crate) fn register_routes(&mut self, app: &mut Pen, url_prefix: &str) {
+        let url_prefix = url_prefix.trim_right_matches('/');
+        for (rule, methods, endpoint, view_func) in self.deferred_routes.drain(..) {
+            let matcher = rule.into_matcher(url_prefix, &app.url_map);
+            app.add_boxed_url_rule(matcher, &methods, &endpoint, view_func);
+        }
+        if self.static_folder.is_some() {
+            let static_url_path = match self.static_url_path {
+                Some(ref static_url_path) => static_url_path.trim_right_matches('/').to_string(),
+                None => String::from("/static"),
+            };
+            let rule = format!("{}{}/<filename:path>", url_prefix, static_url_path);
+            let endpoint = format!("{}.static", self.name);
+            app.route(&rule as &str, &[Method::Get], &endpoint, send_module_static_file);
+        }
+    }
+}
+
+impl fmt::Debug for Module {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<Pen Module {}>", self.name)
+    }
+}
+
+fn send_module_static_file(request: &mut Request) -> PenResult {
+    let module = match request.module_name().and_then(|name| request.app.modules.get(&name)) {
+        Some(module) => module,
+        None => return Err(HTTPError::NotFound.into()),
+    };
+    let mut static_path = PathBuf::from(&module.root_path);
+    if let Some(ref static_folder) = module.static_folder {
+        static_path.push(static_folder);
+    }
+    send_from_directory_range(static_path.to_str().unwrap(), &request.view_args["filename"], false, request.headers().get())
+}
@@ -0,0 +1,616 @@
+//! This module implements streaming parsing of multipart form data.
+//! Unlike `Request::files` the parts are read one at a time straight
+//! from the body, so large uploads can be written to disk without being
+//! kept in memory.
+
+use std::cell::{Cell, RefCell};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use hyper::header::{Headers, ContentType};
+use hyper::mime::{Mime, TopLevel, SubLevel, Attr};
+
+use formparser::{FormError, check_boundary, check_charset, io_error};
+use signing::generate_token;
+
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// The maximum size of the headers of one part.
+const MAX_HEADER_SIZE: usize = 8 * 1024;
+
+enum State {
+    Preamble,
+    Body,
+    Delimiter,
+    Done,
+}
+
+/// A multipart body, read with `Request::multipart`.
+///
+/// ```ignore
+/// let mut multipart = request.multipart()?.max_file_size(10 * 1024 * 1024);
+/// while let Some(mut part) = multipart.next_part()? {
+///     if part.is_file() {
+///         let path = part.save_temp()?;
+///         // ...
+///     } else {
+///         let value = part.text()?;
+///     }
+/// }
+/// ```
+pub struct Multipart<'x> {
+    reader: Box<Read + 'x>,
+    /// `\r\n--boundary`, the body starts with an implicit line break so
+    /// the first boundary is found like all others.
+    delimiter: Vec<u8>,
+    buf: Vec<u8>,
+    eof: bool,
+    state: State,
+    total_read: u64,
+    max_file_size: Option<u64>,
+    max_total_size: Option<u64>,
+    temp_dir: PathBuf,
+    temp_files: &'x RefCell<Vec<PathBuf>>,
+    body_too_large: &'x Cell<bool>,
+}
+
+impl<'x> Multipart<'x> {
+    pub(crate) fn new(reader: Box<Read + 'x>, content_type: Option<&Mime>, temp_dir: PathBuf,
+                      temp_files: &'x RefCell<Vec<PathBuf>>, body_too_large: &'x Cell<bool>)
+        -> Result<Multipart<'x>, FormError>
+    {
+        let mimetype = match content_type {
+            Some(mimetype @ &Mime(TopLevel::Multipart, SubLevel::FormData, _)) => mimetype,
+            _ => return Err(FormError::NotMultipart),
+        };
+        check_charset(mimetype)?;
+        check_boundary(mimetype)?;
+        let boundary = mimetype.get_param(Attr::Boundary).expect("The boundary was checked");
+        Ok(Multipart {
+            reader: reader,
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            buf: b"\r\n".to_vec(),
+            eof: false,
+            state: State::Preamble,
+            total_read: 0,
+            max_file_size: None,
+            max_total_size: None,
+            temp_dir: temp_dir,
+            temp_files: temp_files,
+            body_too_large: body_too_large,
+        })
+    }
+
+    /// The maximum size of a single uploaded file, reading more fails
+    /// with `FormError::SizeExceeded`.
+    pub fn max_file_size(mut self, max_file_size: u64) -> Multipart<'x> {
+        self.max_file_size = Some(max_file_size);
+        self
+    }
+
+    /// The maximum size of the whole body.  The `MAX_CONTENT_LENGTH` is
+    /// enforced as well.
+    pub fn max_total_size(mut self, max_total_size: u64) -> Multipart<'x> {
+        self.max_total_size = Some(max_total_size);
+        self
+    }
+
+    /// The next part of the body, `None` after the last one.  The rest of
+    /// the previous part is skipped.
+    pub fn next_part<'m>(&'m mut self) -> Result<Option<Part<'m, 'x>>, FormError> {
+        loop {
+            match self.state {
+                State::Preamble => self.skip_preamble()?,
+                State::Body => { self.read_body(&mut [0; CHUNK_SIZE])?; },
+                State::Delimiter => break,
+                State::Done => return Ok(None),
+            }
+        }
+        if self.read_delimiter_end()? {
+            self.state = State::Done;
+            return Ok(None);
+        }
+        let headers = self.read_headers()?;
+        self.state = State::Body;
+        Ok(Some(Part::new(self, headers)))
+    }
+
+    /// Read more of the body into the buffer, `false` at the end.
+    fn fill(&mut self) -> Result<bool, FormError> {
+        if self.eof {
+            return Ok(false);
+        }
+        let mut chunk = [0; CHUNK_SIZE];
+        let read = loop {
+            match self.reader.read(&mut chunk) {
+                Ok(read) => break read,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) if self.body_too_large.get() => return Err(FormError::SizeExceeded),
+                Err(e) => return Err(io_error(&e)),
+            }
+        };
+        if read == 0 {
+            self.eof = true;
+            return Ok(false);
+        }
+        self.total_read += read as u64;
+        if let Some(max_total_size) = self.max_total_size {
+            if self.total_read > max_total_size {
+                return Err(FormError::SizeExceeded);
+            }
+        }
+        self.buf.extend_from_slice(&chunk[..read]);
+        Ok(true)
+    }
+
+    /// Fill the buffer until it holds at least `len` bytes.
+    fn fill_to(&mut self, len: usize) -> Result<(), FormError> {
+        while self.buf.len() < len {
+            if !self.fill()? {
+                return Err(FormError::Truncated);
+            }
+        }
+        Ok(())
+    }
+
+    /// The number of buffered bytes that surely are not part of the next
+    /// delimiter and the position of the delimiter if it is buffered.
+    fn scan(&self) -> (usize, bool) {
+        match find(&self.buf, &self.delimiter) {
+            Some(index) => (index, true),
+            None => (self.buf.len().saturating_sub(self.delimiter.len() - 1), false),
+        }
+    }
+
+    fn skip_preamble(&mut self) -> Result<(), FormError> {
+        loop {
+            let (len, found) = self.scan();
+            if found {
+                self.buf.drain(..len + self.delimiter.len());
+                self.state = State::Delimiter;
+                return Ok(());
+            }
+            self.buf.drain(..len);
+            if !self.fill()? {
+                return Err(FormError::BadBoundary);
+            }
+        }
+    }
+
+    /// Read from the body of the current part, 0 at its end.
+    fn read_body(&mut self, out: &mut [u8]) -> Result<usize, FormError> {
+        loop {
+            let (len, found) = self.scan();
+            if len > 0 {
+                let len = len.min(out.len());
+                out[..len].copy_from_slice(&self.buf[..len]);
+                self.buf.drain(..len);
+                return Ok(len);
+            }
+            if found {
+                self.buf.drain(..self.delimiter.len());
+                self.state = State::Delimiter;
+                return Ok(0);
+            }
+            if !self.fill()? {
+                return Err(FormError::Truncated);
+            }
+        }
+    }
+
+    /// Read the rest of the delimiter line, `true` for the closing
+    /// delimiter.
+    fn read_delimiter_end(&mut self) -> Result<bool, FormError> {
+        self.fill_to(2)?;
+        if self.buf.starts_with(b"--") {
+            return Ok(true);
+        }
+        loop {
+            let padding = self.buf.iter().take_while(|&&b| b == b' ' || b == b'\t').count();
+            self.buf.drain(..padding);
+            if self.buf.len() >= 2 {
+                break;
+            }
+            self.fill_to(self.buf.len() + 1)?;
+        }
+        if !self.buf.starts_with(b"\r\n") {
+            return Err(FormError::Malformed("missing line break after the boundary".to_string()));
+        }
+        self.buf.drain(..2);
+        Ok(false)
+    }
+
+    fn read_headers(&mut self) -> Result<Headers, FormError> {
+        let mut headers = Headers::new();
+        self.fill_to(2)?;
+        if self.buf.starts_with(b"\r\n") {
+            self.buf.drain(..2);
+            return Ok(headers);
+        }
+        let end = loop {
+            if let Some(end) = find(&self.buf, b"\r\n\r\n") {
+                break end;
+            }
+            if self.buf.len() > MAX_HEADER_SIZE {
+                return Err(FormError::Malformed("the part headers are too large".to_string()));
+            }
+            if !self.fill()? {
+                return Err(FormError::Truncated);
+            }
+        };
+        {
+            let raw = ::std::str::from_utf8(&self.buf[..end]).map_err(|_| FormError::BadEncoding)?;
+            for line in raw.split("\r\n") {
+                let index = match line.find(':') {
+                    Some(index) => index,
+                    None => return Err(FormError::Malformed(format!("invalid part header {:?}", line))),
+                };
+                headers.append_raw(line[..index].trim().to_string(), line[index + 1..].trim().as_bytes().to_vec());
+            }
+        }
+        self.buf.drain(..end + 4);
+        Ok(headers)
+    }
+}
+
+/// One part of a multipart body.  Read its contents with `Read`, `text`
+/// or one of the `save` methods.
+pub struct Part<'m, 'x: 'm> {
+    multipart: &'m mut Multipart<'x>,
+    headers: Headers,
+    name: Option<String>,
+    filename: Option<String>,
+    size: u64,
+}
+
+impl<'m, 'x> Part<'m, 'x> {
+    fn new(multipart: &'m mut Multipart<'x>, headers: Headers) -> Part<'m, 'x> {
+        let (name, filename) = match headers.get_raw("Content-Disposition").and_then(|values| values.first()) {
+            Some(value) => {
+                let params = disposition_params(&String::from_utf8_lossy(value));
+                let param = |key: &str| params.iter()
+                    .find(|&(name, _)| name.eq_ignore_ascii_case(key))
+                    .map(|(_, value)| value.clone());
+                (param("name"), param("filename"))
+            },
+            None => (None, None),
+        };
+        Part {
+            multipart: multipart,
+            headers: headers,
+            name: name,
+            filename: filename,
+            size: 0,
+        }
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    /// The name of the form field.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The file name sent by the client.  Do not use it as path without
+    /// checking it.
+    pub fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    pub fn content_type(&self) -> Option<&Mime> {
+        self.headers.get::<ContentType>().map(|content_type| &content_type.0)
+    }
+
+    /// Whether the part is an uploaded file rather than a form field.
+    pub fn is_file(&self) -> bool {
+        self.filename.is_some()
+    }
+
+    /// The number of bytes read from the part so far.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_chunk(&mut self, buf: &mut [u8]) -> Result<usize, FormError> {
+        if !matches!(self.multipart.state, State::Body) {
+            return Ok(0);
+        }
+        let read = self.multipart.read_body(buf)?;
+        self.size += read as u64;
+        match self.multipart.max_file_size {
+            Some(max_file_size) if self.is_file() && self.size > max_file_size => Err(FormError::SizeExceeded),
+            _ => Ok(read),
+        }
+    }
+
+    /// Read the whole part as UTF-8 text.
+    pub fn text(&mut self) -> Result<String, FormError> {
+        let mut bytes = Vec::new();
+        self.copy_to(&mut bytes)?;
+        String::from_utf8(bytes).map_err(|_| FormError::BadEncoding)
+    }
+
+    /// Copy the contents of the part to a writer, returns the number of
+    /// bytes written.
+    pub fn copy_to<W: Write>(&mut self, writer: &mut W) -> Result<u64, FormError> {
+        let mut buf = [0; CHUNK_SIZE];
+        let mut written = 0;
+        loop {
+            let read = self.read_chunk(&mut buf)?;
+            if read == 0 {
+                return Ok(written);
+            }
+            writer.write_all(&buf[..read]).map_err(|e| FormError::Storage(e.to_string()))?;
+            written += read as u64;
+        }
+    }
+
+    /// Write the contents of the part to a file.  The file is removed
+    /// again if reading the part fails.
+    pub fn save_as<P: AsRef<Path>>(&mut self, path: P) -> Result<u64, FormError> {
+        let path = path.as_ref();
+        let mut file = File::create(path).map_err(|e| FormError::Storage(e.to_string()))?;
+        let result = self.copy_to(&mut file)
+            .and_then(|written| file.flush().map(|_| written).map_err(|e| FormError::Storage(e.to_string())));
+        if result.is_err() {
+            let _ = fs::remove_file(path);
+        }
+        result
+    }
+
+    /// Write the contents of the part to a new file with a random name
+    /// in the directory and return its path.  The extension of the
+    /// client's file name is kept.
+    pub fn save_to_dir<P: AsRef<Path>>(&mut self, dir: P) -> Result<PathBuf, FormError> {
+        let path = dir.as_ref().join(self.random_filename());
+        self.save_as(&path)?;
+        Ok(path)
+    }
+
+    /// Write the contents of the part to a temporary file, which is
+    /// removed when the request is torn down.  The directory is the
+    /// `UPLOAD_TEMP_DIR` config value or the system's temporary directory.
+    pub fn save_temp(&mut self) -> Result<PathBuf, FormError> {
+        let path = self.multipart.temp_dir.join(self.random_filename());
+        self.multipart.temp_files.borrow_mut().push(path.clone());
+        self.save_as(&path)?;
+        Ok(path)
+    }
+
+    fn random_filename(&self) -> String {
+        let extension = self.filename.as_ref()
+            .and_then(|filename| Path::new(filename).extension())
+            .and_then(|extension| extension.to_str())
+            .filter(|extension| extension.len() <= 16 && extension.chars().all(|c| c.is_ascii_alphanumeric()));
+        match extension {
+            Some(extension) => format!("pen-upload-{}.{}", generate_token(), extension),
+            None => format!("pen-upload-{}", generate_token()),
+        }
+    }
+}
+
+impl<'m, 'x> Read for Part<'m, 'x> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_chunk(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// Remove the temporary files of a request.
+pub(crate) fn remove_temp_files(temp_files: &RefCell<Vec<PathBuf>>) {
+    for path in temp_files.borrow_mut().drain(..) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// Parse the parameters of a `Content-Disposition` header, values may be
+/// quoted strings with escapes.
+fn disposition_params(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut chars = value.chars().peekable();
+    // Skip the disposition type.
+    for c in chars.by_ref() {
+        if c == ';' {
+            break;
+        }
+    }
+    loop {
+        let name: String = chars.by_ref().take_while(|&c| c != '=').collect();
+        let name = name.trim().to_string();
+        if name.is_empty() {
+            return params;
+        }
+        while chars.peek() == Some(&' ') {
+            chars.next();
+        }
+        let mut param = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => param.extend(chars.next()),
+                    c => param.push(c),
+                }
+            }
+            for c in chars.by_ref() {
+                if c == ';' {
+                    break;
+                }
+            }
+        } else {
+            param = chars.by_ref().take_while(|&c| c != ';').collect();
+            param = param.trim().to_string();
+        }
+        params.push((name, param));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::env;
+    use std::io::{self, Read};
+    use std::path::PathBuf;
+
+    use hyper::mime::Mime;
+
+    use formparser::FormError;
+    use super::{Multipart, CHUNK_SIZE, disposition_params, remove_temp_files};
+
+    /// Returns at most `chunk` bytes per read.
+    struct SlowReader {
+        body: Vec<u8>,
+        pos: usize,
+        chunk: usize,
+    }
+
+    impl Read for SlowReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = self.chunk.min(buf.len()).min(self.body.len() - self.pos);
+            buf[..len].copy_from_slice(&self.body[self.pos..self.pos + len]);
+            self.pos += len;
+            Ok(len)
+        }
+    }
+
+    fn new_multipart<'x>(body: &[u8], chunk: usize, temp_files: &'x RefCell<Vec<PathBuf>>,
+                     body_too_large: &'x Cell<bool>) -> Multipart<'x> {
+        let reader = SlowReader { body: body.to_vec(), pos: 0, chunk: chunk };
+        let content_type: Mime = "multipart/form-data; boundary=XyZ".parse().unwrap();
+        Multipart::new(Box::new(reader), Some(&content_type), env::temp_dir(), temp_files, body_too_large).unwrap()
+    }
+
+    /// The names and contents of all parts.
+    fn parse(body: &[u8], chunk: usize) -> Result<Vec<(String, String)>, FormError> {
+        let temp_files = RefCell::new(Vec::new());
+        let body_too_large = Cell::new(false);
+        let mut multipart = new_multipart(body, chunk, &temp_files, &body_too_large);
+        let mut parts = Vec::new();
+        while let Some(mut part) = multipart.next_part()? {
+            let name = part.name().unwrap_or("").to_string();
+            parts.push((name, part.text()?));
+        }
+        Ok(parts)
+    }
+
+    fn field(name: &str, value: &str) -> String {
+        format!("--XyZ\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n", name, value)
+    }
+
+    fn file(name: &str, filename: &str, value: &str) -> String {
+        format!("--XyZ\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n\r\n{}\r\n",
+                name, filename, value)
+    }
+
+    fn pairs(parts: &[(&str, &str)]) -> Vec<(String, String)> {
+        parts.iter().map(|&(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn test_parts() {
+        let body = format!("preamble\r\n{}{}--XyZ--\r\nepilogue", field("a", "1"), field("b", "two\r\nlines"));
+        assert_eq!(parse(body.as_bytes(), CHUNK_SIZE).unwrap(), pairs(&[("a", "1"), ("b", "two\r\nlines")]));
+        assert_eq!(parse(b"--XyZ--\r\n", CHUNK_SIZE).unwrap(), pairs(&[]));
+    }
+
+    #[test]
+    fn test_delimiter_split_across_reads() {
+        let header_len = field("a", "").len() - 2;
+        let delimiter_len = "\r\n--XyZ".len();
+        // Let the delimiter after the first value start before, at and
+        // after the end of the first chunk.
+        for offset in 0..delimiter_len + 2 {
+            let value = "x".repeat(CHUNK_SIZE - header_len + 1 - offset);
+            let body = format!("{}{}--XyZ--\r\n", field("a", &value), field("b", "2"));
+            assert_eq!(parse(body.as_bytes(), CHUNK_SIZE).unwrap(), pairs(&[("a", &value), ("b", "2")]));
+        }
+        let body = format!("{}{}--XyZ--\r\n", field("a", "1"), field("b", "2"));
+        assert_eq!(parse(body.as_bytes(), 1).unwrap(), pairs(&[("a", "1"), ("b", "2")]));
+    }
+
+    #[test]
+    fn test_delimiter_padding() {
+        let body = "--XyZ \t \r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n1\r\n--XyZ--\r\n";
+        assert_eq!(parse(body.as_bytes(), CHUNK_SIZE).unwrap(), pairs(&[("a", "1")]));
+        assert_eq!(parse(body.as_bytes(), 1).unwrap(), pairs(&[("a", "1")]));
+        let body = "--XyZx\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n1\r\n--XyZ--\r\n";
+        match parse(body.as_bytes(), CHUNK_SIZE) {
+            Err(FormError::Malformed(_)) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_truncated() {
+        assert_eq!(parse(b"no boundary here", CHUNK_SIZE), Err(FormError::BadBoundary));
+        assert_eq!(parse(field("a", "1").as_bytes(), CHUNK_SIZE), Err(FormError::Truncated));
+        assert_eq!(parse(b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n", CHUNK_SIZE),
+                   Err(FormError::Truncated));
+        assert_eq!(parse(b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nvalue", CHUNK_SIZE),
+                   Err(FormError::Truncated));
+        assert_eq!(parse(b"--XyZ", CHUNK_SIZE), Err(FormError::Truncated));
+    }
+
+    #[test]
+    fn test_max_file_size() {
+        let temp_files = RefCell::new(Vec::new());
+        let body_too_large = Cell::new(false);
+        let body = format!("{}{}--XyZ--\r\n", field("a", "long text"), file("f", "f.txt", "0123456789"));
+        let mut multipart = new_multipart(body.as_bytes(), CHUNK_SIZE, &temp_files, &body_too_large).max_file_size(5);
+        // Form fields are not limited by the file size.
+        assert_eq!(multipart.next_part().unwrap().unwrap().text().unwrap(), "long text");
+        assert_eq!(multipart.next_part().unwrap().unwrap().text(), Err(FormError::SizeExceeded));
+
+        let mut multipart = new_multipart(body.as_bytes(), CHUNK_SIZE, &temp_files, &body_too_large).max_file_size(10);
+        multipart.next_part().unwrap().unwrap();
+        assert_eq!(multipart.next_part().unwrap().unwrap().text().unwrap(), "0123456789");
+    }
+
+    #[test]
+    fn test_max_total_size() {
+        let temp_files = RefCell::new(Vec::new());
+        let body_too_large = Cell::new(false);
+        let body = format!("{}{}--XyZ--\r\n", field("a", "1"), field("b", &"x".repeat(100)));
+        let mut multipart = new_multipart(body.as_bytes(), 16, &temp_files, &body_too_large).max_total_size(64);
+        assert_eq!(multipart.next_part().unwrap().unwrap().text().unwrap(), "1");
+        let result = multipart.next_part().and_then(|part| part.unwrap().text());
+        assert_eq!(result, Err(FormError::SizeExceeded));
+
+        let mut multipart = new_multipart(body.as_bytes(), 16, &temp_files, &body_too_large).max_total_size(body.len() as u64);
+        while multipart.next_part().unwrap().is_some() {}
+    }
+
+    #[test]
+    fn test_disposition_params() {
+        let params = disposition_params(r#"form-data; name="a\"b"; filename="c;d \\ e.txt""#);
+        assert_eq!(params, pairs(&[("name", "a\"b"), ("filename", "c;d \\ e.txt")]));
+        let params = disposition_params("form-data;name=plain ; filename = \"x\"");
+        assert_eq!(params, pairs(&[("name", "plain"), ("filename", "x")]));
+        assert_eq!(disposition_params("form-data"), pairs(&[]));
+    }
+
+    #[test]
+    fn test_remove_temp_files() {
+        let temp_files = RefCell::new(Vec::new());
+        let body_too_large = Cell::new(false);
+        let body = format!("{}--XyZ--\r\n", file("f", "upload.txt", "contents"));
+        let path = {
+            let mut multipart = new_multipart(body.as_bytes(), CHUNK_SIZE, &temp_files, &body_too_large);
+            let mut part = multipart.next_part().unwrap().unwrap();
+            part.save_temp().unwrap()
+        };
+        assert_eq!(path.extension().and_then(|extension| extension.to_str()), Some("txt"));
+        assert!(path.exists());
+        assert_eq!(*temp_files.borrow(), vec![path.clone()]);
+        remove_temp_files(&temp_files);
+        assert!(!path.exists());
+        assert!(temp_files.borrow().is_empty());
+    }
+}
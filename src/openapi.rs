@@ -0,0 +1,476 @@
+//! This module generates an OpenAPI 3 document from the registered
+//! routes.  Path parameters are taken from the rule converters, views can
+//! be described with summaries, tags and request and response schemas,
+//! either where their route is added or on the `OpenApi` itself.
+//!
+//! ```ignore
+//! app.get("/users/<id:int>", "user", show_user);
+//! app.describe("user", Operation::new()
+//!     .summary("Show a user")
+//!     .tag("users")
+//!     .response(200, "The user", Some(json!({"$ref": "#/components/schemas/User"}))));
+//!
+//! OpenApi::new("Users", "1.0")
+//!     .schema("User", json!({"type": "object"}))
+//!     .register(&mut app, "/openapi.json", "openapi");
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+
+use hyper::method::Method;
+use regex::{Captures, Regex};
+use serde_json::{Map as JsonMap, Value};
+
+use app::Pen;
+use routing::Rule;
+use types::PenResult;
+use wrappers::{Request, Response};
+
+/// The documentation of an operation, one method of an endpoint.
+#[derive(Clone, Debug, Default)]
+pub struct Operation {
+    summary: Option<String>,
+    description: Option<String>,
+    tags: Vec<String>,
+    parameters: Vec<Value>,
+    request_body: Option<(String, Value)>,
+    responses: BTreeMap<u16, (String, Option<Value>)>,
+    deprecated: bool,
+}
+
+impl Operation {
+    pub fn new() -> Operation {
+        Operation::default()
+    }
+
+    pub fn summary(mut self, summary: &str) -> Operation {
+        self.summary = Some(summary.to_string());
+        self
+    }
+
+    pub fn description(mut self, description: &str) -> Operation {
+        self.description = Some(description.to_string());
+        self
+    }
+
+    pub fn tag(mut self, tag: &str) -> Operation {
+        self.tags.push(tag.to_string());
+        self
+    }
+
+    /// Add a parameter object, like a query or header parameter.  Path
+    /// parameters are added from the rule.
+    pub fn parameter(mut self, parameter: Value) -> Operation {
+        self.parameters.push(parameter);
+        self
+    }
+
+    /// The schema of a JSON request body.
+    pub fn request_body(self, schema: Value) -> Operation {
+        self.request_body_with_type("application/json", schema)
+    }
+
+    pub fn request_body_with_type(mut self, content_type: &str, schema: Value) -> Operation {
+        self.request_body = Some((content_type.to_string(), schema));
+        self
+    }
+
+    /// A response, with the schema of its JSON body if it has one.
+    /// Operations without responses are documented with `200`.
+    pub fn response(mut self, status_code: u16, description: &str, schema: Option<Value>) -> Operation {
+        self.responses.insert(status_code, (description.to_string(), schema));
+        self
+    }
+
+    pub fn deprecated(mut self, deprecated: bool) -> Operation {
+        self.deprecated = deprecated;
+        self
+    }
+
+    fn to_json(&self, operation_id: &str, mut parameters: Vec<Value>) -> Value {
+        let mut operation = JsonMap::new();
+        operation.insert("operationId".to_string(), Value::from(operation_id));
+        if let Some(ref summary) = self.summary {
+            operation.insert("summary".to_string(), Value::from(summary.as_str()));
+        }
+        if let Some(ref description) = self.description {
+            operation.insert("description".to_string(), Value::from(description.as_str()));
+        }
+        if !self.tags.is_empty() {
+            operation.insert("tags".to_string(), Value::from(self.tags.clone()));
+        }
+        parameters.extend(self.parameters.iter().cloned());
+        if !parameters.is_empty() {
+            operation.insert("parameters".to_string(), Value::Array(parameters));
+        }
+        if let Some((ref content_type, ref schema)) = self.request_body {
+            operation.insert("requestBody".to_string(), json_object(vec![
+                ("required", Value::Bool(true)),
+                ("content", json_object(vec![(content_type, json_object(vec![("schema", schema.clone())]))])),
+            ]));
+        }
+        let mut responses = JsonMap::new();
+        for (status_code, (description, schema)) in &self.responses {
+            let mut response = vec![("description", Value::from(description.as_str()))];
+            if let Some(schema) = schema {
+                response.push(("content", json_object(vec![
+                    ("application/json", json_object(vec![("schema", schema.clone())])),
+                ])));
+            }
+            responses.insert(status_code.to_string(), json_object(response));
+        }
+        if responses.is_empty() {
+            responses.insert("200".to_string(), json_object(vec![("description", Value::from("OK"))]));
+        }
+        operation.insert("responses".to_string(), Value::Object(responses));
+        if self.deprecated {
+            operation.insert("deprecated".to_string(), Value::Bool(true));
+        }
+        Value::Object(operation)
+    }
+}
+
+/// The descriptions of endpoints, `None` describes all methods.
+pub(crate) type Operations = HashMap<(String, Option<Method>), Operation>;
+
+/// The OpenAPI document of an application.  Endpoints without a
+/// description are documented from their rules only.  Rules created from
+/// a plain regex and rules with a `path` variable are left out, OpenAPI
+/// path parameters can not contain slashes.
+#[derive(Clone, Debug)]
+pub struct OpenApi {
+    title: String,
+    version: String,
+    description: Option<String>,
+    servers: Vec<String>,
+    operations: Operations,
+    schemas: BTreeMap<String, Value>,
+    excluded: Vec<String>,
+}
+
+impl OpenApi {
+    pub fn new(title: &str, version: &str) -> OpenApi {
+        OpenApi {
+            title: title.to_string(),
+            version: version.to_string(),
+            description: None,
+            servers: Vec::new(),
+            operations: HashMap::new(),
+            schemas: BTreeMap::new(),
+            excluded: vec![String::from("static")],
+        }
+    }
+
+    pub fn description(mut self, description: &str) -> OpenApi {
+        self.description = Some(description.to_string());
+        self
+    }
+
+    /// Add a server url, like `https://api.example.com/v1`.
+    pub fn server(mut self, url: &str) -> OpenApi {
+        self.servers.push(url.to_string());
+        self
+    }
+
+    /// Describe all methods of an endpoint.  This takes precedence over
+    /// the description given with `Pen::describe`.
+    pub fn describe(mut self, endpoint: &str, operation: Operation) -> OpenApi {
+        self.operations.insert((endpoint.to_string(), None), operation);
+        self
+    }
+
+    /// Describe one method of an endpoint, this takes precedence over
+    /// `describe`.
+    pub fn describe_method(mut self, endpoint: &str, method: Method, operation: Operation) -> OpenApi {
+        self.operations.insert((endpoint.to_string(), Some(method)), operation);
+        self
+    }
+
+    /// Add a schema to the components, refer to it as
+    /// `#/components/schemas/<name>`.
+    pub fn schema(mut self, name: &str, schema: Value) -> OpenApi {
+        self.schemas.insert(name.to_string(), schema);
+        self
+    }
+
+    /// Leave the endpoint out of the document.  The `static` endpoint is
+    /// left out by default.
+    pub fn exclude(mut self, endpoint: &str) -> OpenApi {
+        self.excluded.push(endpoint.to_string());
+        self
+    }
+
+    /// The description of a method of an endpoint.
+    fn operation(&self, app: &Pen, endpoint: &str, method: &Method) -> Operation {
+        let method_key = (endpoint.to_string(), Some(method.clone()));
+        let endpoint_key = (endpoint.to_string(), None);
+        self.operations.get(&method_key)
+            .or_else(|| app.operations.get(&method_key))
+            .or_else(|| self.operations.get(&endpoint_key))
+            .or_else(|| app.operations.get(&endpoint_key))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Generate the document for the rules of the application.
+    pub fn document(&self, app: &Pen) -> Value {
+        let mut paths: BTreeMap<String, JsonMap<String, Value>> = BTreeMap::new();
+        for rule in app.url_map.iter_rules() {
+            if rule.matcher.rule.is_none() || self.excluded.contains(&rule.endpoint) {
+                continue;
+            }
+            if rule.matcher.converters().iter().any(|&(_, converter)| converter == "path") {
+                continue;
+            }
+            let (path, parameters) = path_and_parameters(rule);
+            let methods: Vec<Method> = rule.sorted_methods().into_iter()
+                .filter(|method| *method != Method::Head || !rule.methods.contains(&Method::Get))
+                .filter(|method| operation_key(method).is_some())
+                .collect();
+            let path_item = paths.entry(path).or_default();
+            for method in &methods {
+                let operation_id = if methods.len() == 1 {
+                    rule.endpoint.clone()
+                } else {
+                    format!("{}_{}", rule.endpoint, method.to_string().to_lowercase())
+                };
+                let operation = self.operation(app, &rule.endpoint, method);
+                let key = operation_key(method).expect("The methods were filtered");
+                path_item.entry(key).or_insert_with(|| operation.to_json(&operation_id, parameters.clone()));
+            }
+        }
+
+        let mut info = vec![("title", Value::from(self.title.as_str())), ("version", Value::from(self.version.as_str()))];
+        if let Some(ref description) = self.description {
+            info.push(("description", Value::from(description.as_str())));
+        }
+        let mut document = vec![
+            ("openapi", Value::from("3.0.3")),
+            ("info", json_object(info)),
+        ];
+        if !self.servers.is_empty() {
+            let servers = self.servers.iter().map(|url| json_object(vec![("url", Value::from(url.as_str()))])).collect();
+            document.push(("servers", Value::Array(servers)));
+        }
+        let paths = paths.into_iter().map(|(path, item)| (path, Value::Object(item))).collect();
+        document.push(("paths", Value::Object(paths)));
+        if !self.schemas.is_empty() {
+            let schemas = self.schemas.iter().map(|(name, schema)| (name.clone(), schema.clone())).collect();
+            document.push(("components", json_object(vec![("schemas", Value::Object(schemas))])));
+        }
+        json_object(document)
+    }
+
+    /// Check that the endpoints described here and with `Pen::describe`
+    /// exist and have the described methods.  Returns the descriptions
+    /// that do not match a rule, like `user` or `user POST`.
+    pub fn validate(&self, app: &Pen) -> Result<(), Vec<String>> {
+        let mut unknown: Vec<String> = self.operations.keys().chain(app.operations.keys())
+            .filter(|&(endpoint, method)| !app.url_map.iter_rules().any(|rule| {
+                rule.endpoint == *endpoint && method.as_ref().is_none_or(|method| rule.methods.contains(method))
+            }))
+            .map(|(endpoint, method)| match *method {
+                Some(ref method) => format!("{} {}", endpoint, method),
+                None => endpoint.clone(),
+            })
+            .collect();
+        unknown.sort();
+        unknown.dedup();
+        if unknown.is_empty() { Ok(()) } else { Err(unknown) }
+    }
+
+    /// Serve the document as JSON at the rule with the given endpoint,
+    /// which is left out of the document.  The document is generated for
+    /// each request so it includes routes added later, but the
+    /// descriptions are validated against the routes registered so far.
+    /// Panics if a description does not match a rule.
+    pub fn register(mut self, app: &mut Pen, rule: &str, endpoint: &str) {
+        if let Err(unknown) = self.validate(app) {
+            panic!("OpenAPI descriptions for unknown endpoints: {}", unknown.join(", "));
+        }
+        self.excluded.push(endpoint.to_string());
+        app.get(rule, endpoint, move |request: &mut Request| -> PenResult {
+            Response::json(&self.document(request.app))
+        });
+    }
+}
+
+fn json_object<K: AsRef<str>>(entries: Vec<(K, Value)>) -> Value {
+    Value::Object(entries.into_iter().map(|(key, value)| (key.as_ref().to_string(), value)).collect())
+}
+
+/// The key of an operation in a path item, `None` for methods OpenAPI
+/// does not support.
+fn operation_key(method: &Method) -> Option<String> {
+    match *method {
+        Method::Get | Method::Put | Method::Post | Method::Delete | Method::Options |
+        Method::Head | Method::Patch | Method::Trace => Some(method.to_string().to_lowercase()),
+        _ => None,
+    }
+}
+
+/// The schema of a path parameter, derived from its converter.
+fn parameter_schema(rule: &Rule, variable: &str, converter: &str) -> Value {
+    match converter {
+        "int" => json_object(vec![("type", Value::from("integer")), ("minimum", Value::from(0))]),
+        "float" => json_object(vec![("type", Value::from("number")), ("minimum", Value::from(0))]),
+        "default" | "string" | "path" => json_object(vec![("type", Value::from("string"))]),
+        _ => match rule.matcher.converter(variable) {
+            Some(converter) => json_object(vec![
+                ("type", Value::from("string")),
+                ("pattern", Value::from(format!("^{}$", converter.regex()))),
+            ]),
+            None => json_object(vec![("type", Value::from("string"))]),
+        },
+    }
+}
+
+/// Turn the rule into an OpenAPI path like `/users/{id}` and the
+/// parameter objects of its variables.  Variables in the query part of
+/// the rule become query parameters.
+fn path_and_parameters(rule: &Rule) -> (String, Vec<Value>) {
+    let variable_re = Regex::new(r"<(?:([a-zA-Z_][a-zA-Z0-9_]*):)?([a-zA-Z_][a-zA-Z0-9_]*)>").unwrap();
+    let rule_string = rule.rule_string();
+    let (path, query) = match rule_string.find('?') {
+        Some(index) => (&rule_string[..index], &rule_string[index..]),
+        None => (&rule_string[..], ""),
+    };
+    let path = variable_re.replace_all(path, |caps: &Captures| {
+        let variable = caps.get(1).unwrap_or_else(|| caps.get(2).unwrap()).as_str();
+        format!("{{{}}}", variable)
+    }).into_owned();
+    let query_variables: Vec<String> = variable_re.captures_iter(query)
+        .map(|caps| caps.get(1).unwrap_or_else(|| caps.get(2).unwrap()).as_str().to_string())
+        .collect();
+    let parameters = rule.matcher.converters().into_iter()
+        .map(|(variable, converter)| {
+            let location = if query_variables.iter().any(|query_variable| query_variable == variable) { "query" } else { "path" };
+            json_object(vec![
+                ("name", Value::from(variable)),
+                ("in", Value::from(location)),
+                ("required", Value::Bool(true)),
+                ("schema", parameter_schema(rule, variable, converter)),
+            ])
+        })
+        .collect();
+    (path, parameters)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::any::Any;
+
+    use hyper::method::Method;
+    use serde_json::{self, Value};
+
+    use app::Pen;
+    use routing::Converter;
+    use testing::Client;
+    use wrappers::Request;
+    use super::{OpenApi, Operation};
+
+    struct SlugConverter;
+
+    impl Converter for SlugConverter {
+        fn regex(&self) -> &str { "[a-z0-9-]+" }
+
+        fn to_value(&self, value: &str) -> Option<Box<Any + Send + Sync>> {
+            Some(Box::new(value.to_owned()))
+        }
+    }
+
+    fn view(_: &mut Request) -> &'static str {
+        "view"
+    }
+
+    fn app() -> Pen {
+        let mut app = Pen::new("/web/pen");
+        app.register_converter("slug", SlugConverter);
+        app.get("/users/<id:int>", "user", view);
+        app.describe("user", Operation::new().summary("Show a user").tag("users"));
+        app.route("/prices/<price:float>", &[Method::Get, Method::Put], "price", view);
+        app.describe_method("price", Method::Put, Operation::new().request_body(serde_json::from_str(r#"{"type": "number"}"#).unwrap()));
+        app.get("/posts/<slug:slug>", "post", view);
+        app.get("/search?q=<q>&page=<page:int>", "search", view);
+        app.get("/files/<name:path>", "file", view);
+        app
+    }
+
+    #[test]
+    fn test_document() {
+        let app = app();
+        let api = OpenApi::new("Pen", "1.0")
+            .describe("post", Operation::new().response(200, "The post", None).deprecated(true));
+        let expected: Value = serde_json::from_str(r#"{
+            "openapi": "3.0.3",
+            "info": {"title": "Pen", "version": "1.0"},
+            "paths": {
+                "/posts/{slug}": {
+                    "get": {
+                        "operationId": "post",
+                        "parameters": [{"name": "slug", "in": "path", "required": true,
+                                        "schema": {"type": "string", "pattern": "^[a-z0-9-]+$"}}],
+                        "responses": {"200": {"description": "The post"}},
+                        "deprecated": true
+                    }
+                },
+                "/prices/{price}": {
+                    "get": {
+                        "operationId": "price_get",
+                        "parameters": [{"name": "price", "in": "path", "required": true,
+                                        "schema": {"type": "number", "minimum": 0}}],
+                        "responses": {"200": {"description": "OK"}}
+                    },
+                    "put": {
+                        "operationId": "price_put",
+                        "parameters": [{"name": "price", "in": "path", "required": true,
+                                        "schema": {"type": "number", "minimum": 0}}],
+                        "requestBody": {"required": true, "content": {"application/json": {"schema": {"type": "number"}}}},
+                        "responses": {"200": {"description": "OK"}}
+                    }
+                },
+                "/search": {
+                    "get": {
+                        "operationId": "search",
+                        "parameters": [
+                            {"name": "q", "in": "query", "required": true, "schema": {"type": "string"}},
+                            {"name": "page", "in": "query", "required": true, "schema": {"type": "integer", "minimum": 0}}
+                        ],
+                        "responses": {"200": {"description": "OK"}}
+                    }
+                },
+                "/users/{id}": {
+                    "get": {
+                        "operationId": "user",
+                        "summary": "Show a user",
+                        "tags": ["users"],
+                        "parameters": [{"name": "id", "in": "path", "required": true,
+                                        "schema": {"type": "integer", "minimum": 0}}],
+                        "responses": {"200": {"description": "OK"}}
+                    }
+                }
+            }
+        }"#).unwrap();
+        assert_eq!(api.document(&app), expected);
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut app = app();
+        app.describe("usr", Operation::new());
+        let api = OpenApi::new("Pen", "1.0")
+            .describe("user", Operation::new())
+            .describe_method("user", Method::Post, Operation::new());
+        assert_eq!(api.validate(&app), Err(vec!["user POST".to_string(), "usr".to_string()]));
+    }
+
+    #[test]
+    fn test_register() {
+        let mut app = app();
+        OpenApi::new("Pen", "1.0").register(&mut app, "/openapi.json", "openapi");
+        let response = Client::new(&app).get("/openapi.json");
+        let document: Value = serde_json::from_str(&response.text()).unwrap();
+        assert_eq!(document["paths"]["/users/{id}"]["get"]["summary"], "Show a user");
+        assert!(document["paths"].get("/openapi.json").is_none());
+    }
+}
@@ -1,9 +1,17 @@
 //! This module implements the dispatcher.
 
+use std::any::Any;
 use std::collections::HashMap;
 use std::collections::HashSet;
-use regex::Regex;
+use std::cmp::Ordering;
+use std::error::Error;
+use std::fmt;
+use std::slice;
+use std::sync::{Arc, RwLock};
+use regex::{Regex, RegexSet, SetMatches};
 use regex::escape as regex_quote;
+use url::form_urlencoded;
+use url::percent_encoding::{utf8_percent_encode, DEFAULT_ENCODE_SET, PATH_SEGMENT_ENCODE_SET};
 
 use hyper::method::Method;
 
@@ -35,10 +43,10 @@ fn parse_rule(rule: &str) -> Vec<(Option<&str>, &str)> {
                 if static_part.is_some() {
                     rule_parts.push((None, static_part.unwrap().as_str()));
                 }
-                let variable = caps.name("variable").unwrap();
-                let converter = match caps.name("converter") {
-                    Some(converter) => converter.as_str(),
-                    None => "default",
+                // `<name>` is a variable using the default converter.
+                let (variable, converter) = match caps.name("variable") {
+                    Some(variable) => (variable, caps.name("converter").unwrap().as_str()),
+                    None => (caps.name("converter").unwrap(), "default"),
                 };
                 if used_names.contains(variable.as_str()) {
                     panic!("variable name {} used twice.", variable.as_str());
@@ -61,63 +69,193 @@ fn parse_rule(rule: &str) -> Vec<(Option<&str>, &str)> {
     rule_parts
 }
 
+/// A converter turns the part of the url matched by a rule placeholder
+/// into a value and formats values back into url segments.
+///
+/// Converters are registered on the `Map` by name and referenced from
+/// rules as `<variable:name>`.
+pub trait Converter: Send + Sync {
+    /// The regular expression used to match the url segment.
+    fn regex(&self) -> &str;
+
+    /// Parse a matched url segment.  Returning `None` rejects the match.
+    fn to_value(&self, value: &str) -> Option<Box<Any + Send + Sync>>;
+
+    /// Format a value into a url segment.
+    fn to_url(&self, value: &str) -> String {
+        utf8_percent_encode(value, PATH_SEGMENT_ENCODE_SET).to_string()
+    }
+
+    /// The priority of the converter when rules overlap, rules with a
+    /// lower weight are tried first.  Static url parts weigh 0, the
+    /// default converter 100.
+    fn weight(&self) -> u32 { 100 }
+}
+
+impl fmt::Debug for Converter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<Converter {}>", self.regex())
+    }
+}
+
+/// The default converter.  Accepts any text without a slash.
+pub struct StringConverter;
+
+impl Converter for StringConverter {
+    fn regex(&self) -> &str { "[^/]{1,}" }
+
+    fn to_value(&self, value: &str) -> Option<Box<Any + Send + Sync>> {
+        Some(Box::new(value.to_owned()))
+    }
+}
+
+/// Accepts positive integers and converts them to `i64`.
+pub struct IntConverter;
+
+impl Converter for IntConverter {
+    fn regex(&self) -> &str { r"\d+" }
+
+    fn weight(&self) -> u32 { 50 }
+
+    fn to_value(&self, value: &str) -> Option<Box<Any + Send + Sync>> {
+        value.parse::<i64>().ok().map(|v| Box::new(v) as Box<Any + Send + Sync>)
+    }
+}
+
+/// Accepts positive floating point values and converts them to `f64`.
+pub struct FloatConverter;
+
+impl Converter for FloatConverter {
+    fn regex(&self) -> &str { r"\d+\.\d+" }
+
+    fn weight(&self) -> u32 { 50 }
+
+    fn to_value(&self, value: &str) -> Option<Box<Any + Send + Sync>> {
+        value.parse::<f64>().ok().map(|v| Box::new(v) as Box<Any + Send + Sync>)
+    }
+}
+
+/// Like the default converter, but also accepts slashes.
+pub struct PathConverter;
+
+impl Converter for PathConverter {
+    fn regex(&self) -> &str { "[^/].*?" }
+
+    fn to_value(&self, value: &str) -> Option<Box<Any + Send + Sync>> {
+        Some(Box::new(value.to_owned()))
+    }
+
+    fn to_url(&self, value: &str) -> String {
+        utf8_percent_encode(value, DEFAULT_ENCODE_SET).to_string()
+    }
+
+    fn weight(&self) -> u32 { 200 }
+}
+
+/// A map of converter names to converters.
+pub type Converters = HashMap<String, Arc<Converter>>;
+
+fn default_converters() -> Converters {
+    let mut converters: Converters = HashMap::new();
+    converters.insert("default".to_owned(), Arc::new(StringConverter));
+    converters.insert("string".to_owned(), Arc::new(StringConverter));
+    converters.insert("int".to_owned(), Arc::new(IntConverter));
+    converters.insert("float".to_owned(), Arc::new(FloatConverter));
+    converters.insert("path".to_owned(), Arc::new(PathConverter));
+    converters
+}
+
 /// The matcher holds the url regex object.
 #[derive(Clone, Debug)]
 pub struct Matcher {
     pub regex: Regex,
     pub matches_query: bool,
+    /// The rule string this matcher was compiled from, if any.
+    pub rule: Option<String>,
+    /// The parsed rule parts in the form `(Option<converter>, variable)`,
+    /// used for URL building.  Empty for plain regex matchers.
+    parts: Vec<(Option<String>, String)>,
+    /// The converter used for each variable of the rule.
+    converters: HashMap<String, Arc<Converter>>,
+    is_branch: bool,
+    /// The weight of each path segment, see `Converter::weight`.
+    weights: Vec<u32>,
+    /// The regex without variable names, equal for rules matching the
+    /// same urls.
+    pattern: String,
 }
 
 impl Matcher {
     pub fn new(regex: Regex, matches_query: bool) -> Matcher {
+        let pattern = regex.as_str().to_owned();
         Matcher {
             regex: regex,
             matches_query: matches_query,
+            rule: None,
+            parts: vec![],
+            converters: HashMap::new(),
+            is_branch: false,
+            weights: vec![u32::MAX],
+            pattern: pattern,
         }
     }
-}
 
-/// Rule strings basically are just normal URL paths with placeholders in
-/// the format `<name:converter>` where the converter are optional.
-/// Currently we support following converters:
-///
-/// - string(default)
-/// - int
-/// - float
-/// - path
-///
-/// If no converter is defined the `default` converter is used which means `string`.
-///
-/// URL rules that end with a slash are branch URLs, others are leaves.
-/// All branch URLs that are matched without a trailing slash will trigger a
-/// redirect to the same URL with the missing slash appended.
-/// We have a url without a trailing slash for branch url rule.
-/// So we redirect to the same url but with a trailing slash.
-impl<'a> From<&'a str> for Matcher {
-    fn from(rule: &'a str) -> Matcher {
+    /// Compile a rule string using the given converters.
+    ///
+    /// Rule strings basically are just normal URL paths with placeholders in
+    /// the format `<name:converter>` where the converter are optional.
+    /// The following converters are always available:
+    ///
+    /// - string(default)
+    /// - int
+    /// - float
+    /// - path
+    ///
+    /// If no converter is defined the `default` converter is used which means `string`.
+    ///
+    /// URL rules that end with a slash are branch URLs, others are leaves.
+    /// All branch URLs that are matched without a trailing slash will trigger a
+    /// redirect to the same URL with the missing slash appended.
+    /// We have a url without a trailing slash for branch url rule.
+    /// So we redirect to the same url but with a trailing slash.
+    pub fn compile(rule: &str, converters: &Converters) -> Matcher {
         if !rule.starts_with('/') {
             panic!("urls must start with a leading slash");
         }
+        Matcher::compile_template(rule, converters)
+    }
+
+    /// Compile a rule string that is not necessarily a path, like a
+    /// host template.
+    fn compile_template(rule: &str, converters: &Converters) -> Matcher {
         let matches_query = rule.contains('?');
         let is_branch = rule.ends_with('/');
 
         // Compiles the regular expression
+        let rule_parts = parse_rule(rule.trim_right_matches('/'));
         let mut regex_parts: Vec<String> = Vec::new();
-        for (converter, variable) in parse_rule(rule.trim_right_matches('/')) {
+        let mut pattern_parts: Vec<String> = Vec::new();
+        let mut weights: Vec<u32> = Vec::new();
+        let mut variable_converters = HashMap::new();
+        for &(converter, variable) in &rule_parts {
             match converter {
                 Some(converter) => {
-                    let re = match converter {
-                        "string" | "default" => "[^/]{1,}",
-                        "int" => r"\d+",
-                        "float" => r"\d+\.\d+",
-                        "path" => "[^/].*?",
-                        _ => { panic!("the converter {} does not exist", converter) }
+                    let converter = match converters.get(converter) {
+                        Some(converter) => converter.clone(),
+                        None => { panic!("the converter {} does not exist", converter) }
                     };
-                    regex_parts.push(format!("(?P<{}>{})", variable, re));
+                    regex_parts.push(format!("(?P<{}>{})", variable, converter.regex()));
+                    pattern_parts.push(format!("({})", converter.regex()));
+                    if let Some(weight) = weights.last_mut() {
+                        *weight = (*weight).max(converter.weight());
+                    }
+                    variable_converters.insert(variable.to_owned(), converter);
                 },
                 None => {
                     let escaped_variable = regex_quote(variable);
-                    regex_parts.push(escaped_variable);
+                    regex_parts.push(escaped_variable.clone());
+                    pattern_parts.push(escaped_variable);
+                    weights.extend(variable.matches('/').map(|_| 0));
                 }
             }
         }
@@ -125,7 +263,124 @@ impl<'a> From<&'a str> for Matcher {
             regex_parts.push(String::from("(?P<__suffix__>/?)"));
         }
         let regex = format!(r"^{}$", join_string(&regex_parts, ""));
-        Matcher::new(Regex::new(&regex).unwrap(), matches_query)
+        let mut matcher = Matcher::new(Regex::new(&regex).unwrap(), matches_query);
+        matcher.rule = Some(rule.to_owned());
+        matcher.parts = rule_parts.into_iter()
+            .map(|(converter, variable)| (converter.map(|c| c.to_owned()), variable.to_owned()))
+            .collect();
+        matcher.converters = variable_converters;
+        matcher.is_branch = is_branch;
+        matcher.weights = weights;
+        matcher.pattern = format!("{}{}", join_string(&pattern_parts, ""), if is_branch { "/" } else { "" });
+        matcher
+    }
+
+    /// Compare the priority of two matchers.  Segment by segment static
+    /// parts come before converters, converters are ordered by their
+    /// weight.  If one rule is a prefix of the other the longer one comes
+    /// first.  Plain regex matchers come last.
+    fn cmp_priority(&self, other: &Matcher) -> Ordering {
+        match (self.rule.is_some(), other.rule.is_some()) {
+            (true, false) => return Ordering::Less,
+            (false, true) => return Ordering::Greater,
+            _ => (),
+        }
+        for (weight, other_weight) in self.weights.iter().zip(&other.weights) {
+            if weight != other_weight {
+                return weight.cmp(other_weight);
+            }
+        }
+        other.weights.len().cmp(&self.weights.len())
+    }
+
+    /// The names of the variables of the rule.
+    fn variables(&self) -> Vec<&str> {
+        self.regex.capture_names()
+            .flatten()
+            .filter(|&variable| variable != "__suffix__")
+            .collect()
+    }
+
+    /// Match the text and add the converted variables to the view args.
+    fn captures_into(&self, text: &str, view_args: &mut ViewArgs) -> Option<Result<(), RequestSlashError>> {
+        let caps = self.regex.captures(text)?;
+        for variable in self.variables() {
+            let raw = caps.name(variable).unwrap().as_str();
+            match self.convert(variable, raw) {
+                Some(value) => view_args.insert_converted(variable, raw.to_owned(), value),
+                None => return None,
+            }
+        }
+        if let Some(suffix) = caps.name("__suffix__") {
+            if suffix.as_str().is_empty() {
+                return Some(Err(RequestSlashError));
+            }
+        }
+        Some(Ok(()))
+    }
+
+    /// Run the converter of the given variable on a matched value.
+    /// Variables without a converter (plain regex groups) always pass.
+    fn convert(&self, variable: &str, value: &str) -> Option<Box<Any + Send + Sync>> {
+        match self.converters.get(variable) {
+            Some(converter) => converter.to_value(value),
+            None => Some(Box::new(value.to_owned())),
+        }
+    }
+
+    /// The variables of the rule and the names of their converters, in
+    /// the order they appear.  Empty for plain regex matchers.
+    pub fn converters(&self) -> Vec<(&str, &str)> {
+        self.parts.iter()
+            .filter_map(|(converter, variable)| converter.as_ref().map(|c| (variable.as_str(), c.as_str())))
+            .collect()
+    }
+
+    /// The converter of a variable of the rule.
+    pub fn converter(&self, variable: &str) -> Option<&Converter> {
+        self.converters.get(variable).map(|converter| &**converter)
+    }
+
+    /// Whether the rule ends with a slash, see `Matcher::compile`.
+    pub fn is_branch(&self) -> bool {
+        self.is_branch
+    }
+
+    /// Build the url path for this matcher from the given values.
+    /// Returns the path and the names of the values that were used,
+    /// or `None` if the matcher can not be built from the values.
+    fn build(&self, values: &ViewArgs) -> Option<(String, HashSet<String>)> {
+        if self.rule.is_none() {
+            return None;
+        }
+        let mut url = String::new();
+        let mut used = HashSet::new();
+        for &(ref converter, ref variable) in &self.parts {
+            match *converter {
+                Some(_) => {
+                    let value = match values.get_str(variable) {
+                        Some(value) => value,
+                        None => return None,
+                    };
+                    url.push_str(&self.converters[variable].to_url(value));
+                    used.insert(variable.clone());
+                },
+                None => url.push_str(variable),
+            }
+        }
+        if self.is_branch {
+            url.push('/');
+        }
+        Some((url, used))
+    }
+}
+
+/// Compiles a rule string with the default converters.
+/// Use `Map::add_converter` together with `IntoMatcher` to use
+/// custom converters.
+impl<'a> From<&'a str> for Matcher {
+    fn from(rule: &'a str) -> Matcher {
+        Matcher::compile(rule, &default_converters())
     }
 }
 
@@ -143,6 +398,99 @@ impl From<Regex> for Matcher {
     }
 }
 
+/// Conversion into a `Matcher` using the converters registered on a `Map`.
+pub trait IntoMatcher {
+    fn into_matcher(self, map: &Map) -> Matcher;
+
+    /// Keep the rule to compile it once its url prefix and map are known,
+    /// see `Module::route`.  By default the rule is compiled with the
+    /// default converters right away.
+    fn into_deferred(self) -> DeferredMatcher where Self: Sized {
+        self.into_matcher(&Map::new()).into()
+    }
+}
+
+impl<'a> IntoMatcher for &'a str {
+    fn into_matcher(self, map: &Map) -> Matcher {
+        Matcher::compile(self, &map.converters)
+    }
+
+    fn into_deferred(self) -> DeferredMatcher {
+        DeferredMatcher::Rule(self.to_owned())
+    }
+}
+
+impl<'a> IntoMatcher for &'a String {
+    fn into_matcher(self, map: &Map) -> Matcher {
+        Matcher::compile(self, &map.converters)
+    }
+
+    fn into_deferred(self) -> DeferredMatcher {
+        DeferredMatcher::Rule(self.clone())
+    }
+}
+
+impl IntoMatcher for String {
+    fn into_matcher(self, map: &Map) -> Matcher {
+        Matcher::compile(&self, &map.converters)
+    }
+
+    fn into_deferred(self) -> DeferredMatcher {
+        DeferredMatcher::Rule(self)
+    }
+}
+
+impl IntoMatcher for Regex {
+    fn into_matcher(self, _: &Map) -> Matcher {
+        self.into()
+    }
+
+    fn into_deferred(self) -> DeferredMatcher {
+        DeferredMatcher::Regex(self, false)
+    }
+}
+
+impl IntoMatcher for Matcher {
+    fn into_matcher(self, _: &Map) -> Matcher {
+        self
+    }
+
+    fn into_deferred(self) -> DeferredMatcher {
+        self.into()
+    }
+}
+
+/// A rule that is compiled later under a url prefix, like the rules of a
+/// `Module`.  Rule strings are compiled with the converters of the map
+/// they are added to, regex rules have to match right after the prefix.
+#[derive(Clone, Debug)]
+pub enum DeferredMatcher {
+    Rule(String),
+    /// A regex and whether it matches the query string too.
+    Regex(Regex, bool),
+}
+
+impl DeferredMatcher {
+    pub fn into_matcher(self, url_prefix: &str, map: &Map) -> Matcher {
+        match self {
+            DeferredMatcher::Rule(rule) => Matcher::compile(&(url_prefix.to_owned() + &rule), &map.converters),
+            DeferredMatcher::Regex(regex, matches_query) => {
+                let regex = format!("^{}(?:{})", regex_quote(url_prefix), regex.as_str().trim_left_matches('^'));
+                Matcher::new(Regex::new(&regex).expect("a prefixed regex is valid"), matches_query)
+            },
+        }
+    }
+}
+
+impl From<Matcher> for DeferredMatcher {
+    fn from(matcher: Matcher) -> DeferredMatcher {
+        match matcher.rule {
+            Some(rule) => DeferredMatcher::Rule(rule),
+            None => DeferredMatcher::Regex(matcher.regex, matcher.matches_query),
+        }
+    }
+}
+
 
 /// Request Slash error.
 /// This is for example the case if you request `/foo`
@@ -151,6 +499,86 @@ impl From<Regex> for Matcher {
 pub struct RequestSlashError;
 
 
+/// Build error.
+/// This is returned if no rule for the given endpoint can be built
+/// from the supplied values.
+#[derive(Clone, Debug)]
+pub struct BuildError {
+    pub endpoint: String,
+    pub method: Option<Method>,
+    desc: String,
+}
+
+impl BuildError {
+    pub fn new(endpoint: &str, method: Option<Method>) -> BuildError {
+        let desc = match method {
+            Some(ref method) => format!("could not build url for endpoint '{}' ({})", endpoint, method),
+            None => format!("could not build url for endpoint '{}'", endpoint),
+        };
+        BuildError {
+            endpoint: endpoint.to_owned(),
+            method: method,
+            desc: desc,
+        }
+    }
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.desc)
+    }
+}
+
+impl Error for BuildError {
+    fn description(&self) -> &str {
+        &self.desc
+    }
+}
+
+
+/// A problem with the registered routes, found by `Map::validate` and
+/// `Pen::validate_routes`.
+#[derive(Clone, Debug)]
+pub enum RouteConflict {
+    /// The rule matches the same urls as an earlier rule of
+    /// `shadowed_by` for these methods, so it is never used for them.
+    Ambiguous {
+        rule: String,
+        endpoint: String,
+        shadowed_by: String,
+        methods: Vec<Method>,
+    },
+    /// The endpoint was registered with more than one view function, only
+    /// the last one is used.  `Pen::add_rule` adds more rules to an
+    /// endpoint without another view function.
+    DuplicateEndpoint(String),
+}
+
+impl fmt::Display for RouteConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RouteConflict::Ambiguous { ref rule, ref endpoint, ref shadowed_by, ref methods } => {
+                let methods: Vec<String> = methods.iter().map(|method| method.to_string()).collect();
+                write!(f, "rule '{}' of endpoint '{}' is shadowed by endpoint '{}' for {}",
+                       rule, endpoint, shadowed_by, methods.join(", "))
+            },
+            RouteConflict::DuplicateEndpoint(ref endpoint) => {
+                write!(f, "endpoint '{}' is registered with more than one view function", endpoint)
+            },
+        }
+    }
+}
+
+impl Error for RouteConflict {
+    fn description(&self) -> &str {
+        match *self {
+            RouteConflict::Ambiguous { .. } => "ambiguous url rule",
+            RouteConflict::DuplicateEndpoint(_) => "duplicate endpoint",
+        }
+    }
+}
+
+
 /// The map adapter matched value.
 #[derive(Debug)]
 pub enum MapAdapterMatched {
@@ -170,6 +598,8 @@ pub struct Rule {
     /// The endpoint for this rule.
     pub endpoint: String,
     pub provide_automatic_options: bool,
+    /// The host this rule is restricted to, `None` for all hosts.
+    pub host: Option<HostRule>,
 }
 
 impl Rule {
@@ -192,51 +622,347 @@ impl Rule {
             provide_automatic_options: if all_methods.contains(&Method::Options) { false }
                                        else { all_methods.insert(Method::Options); true },
             methods: all_methods,
+            host: None,
+        }
+    }
+
+    /// The rule string, or the regex for rules created from one.
+    pub fn rule_string(&self) -> String {
+        match self.matcher.rule {
+            Some(ref rule) => rule.clone(),
+            None => self.matcher.regex.as_str().to_owned(),
+        }
+    }
+
+    /// The variables of the host and the rule and the names of their
+    /// converters.
+    pub fn converters(&self) -> Vec<(&str, &str)> {
+        let mut converters = match self.host {
+            Some(ref host) => host.matcher().converters(),
+            None => Vec::new(),
+        };
+        converters.extend(self.matcher.converters());
+        converters
+    }
+
+    /// The methods of the rule sorted by name, without the automatically
+    /// added `OPTIONS`.
+    pub fn sorted_methods(&self) -> Vec<Method> {
+        let mut methods: Vec<Method> = self.explicit_methods().into_iter().collect();
+        methods.sort_by_key(|method| method.to_string());
+        methods
+    }
+
+    /// The methods given when creating the rule, without the added
+    /// `OPTIONS`.
+    fn explicit_methods(&self) -> HashSet<Method> {
+        let mut methods = self.methods.clone();
+        if self.provide_automatic_options {
+            methods.remove(&Method::Options);
+        }
+        methods
+    }
+
+    /// Restrict the rule to a host, the host variables are added to the
+    /// view args.  Panics if a variable is used by the host and the path.
+    pub fn with_host(mut self, host: HostRule) -> Rule {
+        let path_variables = self.matcher.variables();
+        if let Some(variable) = host.matcher().variables().into_iter().find(|v| path_variables.contains(v)) {
+            panic!("variable name {} used twice.", variable);
         }
+        self.host = Some(host);
+        self
     }
 
     /// Check if the rule matches a given path.
     pub fn matched(&self, path: &str) -> Option<Result<ViewArgs, RequestSlashError>> {
-        match self.matcher.regex.captures(path) {
-            Some(caps) => {
-                if let Some(suffix) = caps.name("__suffix__") {
-                    if suffix.as_str().is_empty() {
-                        return Some(Err(RequestSlashError));
-                    }
-                }
-                let mut view_args: HashMap<String, String> = HashMap::new();
-                for variable in self.matcher.regex.capture_names() {
-                    if let Some(variable) = variable {
-                        if variable != "__suffix__" {
-                            view_args.insert(variable.to_owned(), caps.name(variable).unwrap().as_str().to_owned());
-                        }
-                    }
+        let mut view_args = ViewArgs::new();
+        self.matcher.captures_into(path, &mut view_args)
+            .map(|result| result.map(|_| view_args))
+    }
+
+    /// Check if the rule matches a given host and path.  Rules without a
+    /// host match all hosts.
+    pub fn matched_host(&self, host: &str, server_name: Option<&str>, path: &str)
+        -> Option<Result<ViewArgs, RequestSlashError>>
+    {
+        let mut view_args = ViewArgs::new();
+        if let Some(ref host_rule) = self.host {
+            host_rule.captures_into(host, server_name, &mut view_args)?;
+        }
+        self.matcher.captures_into(path, &mut view_args)
+            .map(|result| result.map(|_| view_args))
+    }
+
+    /// Rules with the same path priority are ordered host rules first.
+    fn cmp_priority(&self, other: &Rule) -> Ordering {
+        self.matcher.cmp_priority(&other.matcher)
+            .then(other.host.is_some().cmp(&self.host.is_some()))
+    }
+
+    fn host_pattern(&self) -> Option<String> {
+        self.host.as_ref().map(|host| match *host {
+            HostRule::Host(ref matcher) => format!("host {}", matcher.pattern),
+            HostRule::Subdomain(ref matcher) => format!("subdomain {}", matcher.pattern),
+        })
+    }
+}
+
+
+/// The host a rule is restricted to.  Host templates use the same
+/// converters as rules and are matched against the lowercased host, the
+/// default converter also matches dots.
+#[derive(Clone, Debug)]
+pub enum HostRule {
+    /// A full host like `<tenant>.example.com`.
+    Host(Matcher),
+    /// A subdomain of the `SERVER_NAME` like `<tenant>` or `api`.  The
+    /// empty subdomain matches the server name itself.
+    Subdomain(Matcher),
+}
+
+impl HostRule {
+    pub fn host(template: &str, map: &Map) -> HostRule {
+        HostRule::Host(Matcher::compile_template(template, &map.converters))
+    }
+
+    pub fn subdomain(template: &str, map: &Map) -> HostRule {
+        HostRule::Subdomain(Matcher::compile_template(template, &map.converters))
+    }
+
+    pub fn matcher(&self) -> &Matcher {
+        match *self {
+            HostRule::Host(ref matcher) | HostRule::Subdomain(ref matcher) => matcher,
+        }
+    }
+
+    /// The host or subdomain template.
+    pub fn template(&self) -> &str {
+        self.matcher().rule.as_deref().unwrap_or("")
+    }
+
+    fn captures_into(&self, host: &str, server_name: Option<&str>, view_args: &mut ViewArgs) -> Option<()> {
+        let host = host.to_lowercase();
+        let host = match *self {
+            HostRule::Host(ref matcher) => strip_port(&host, matcher.pattern.contains(':')).to_owned(),
+            HostRule::Subdomain(_) => {
+                let server_name = server_name?.to_lowercase();
+                let host = strip_port(&host, server_name.contains(':'));
+                if host == server_name {
+                    String::new()
+                } else if host.ends_with(&format!(".{}", server_name)) {
+                    host[..host.len() - server_name.len() - 1].to_owned()
+                } else {
+                    return None;
                 }
-                Some(Ok(view_args))
             },
-            None => None,
+        };
+        match self.matcher().captures_into(&host, view_args) {
+            Some(Ok(())) => Some(()),
+            _ => None,
+        }
+    }
+
+    /// Build the host from the values, returns the host and the names of
+    /// the values that were used.
+    fn build(&self, values: &ViewArgs, server_name: Option<&str>) -> Option<(String, HashSet<String>)> {
+        let (host, used) = self.matcher().build(values)?;
+        match *self {
+            HostRule::Host(_) => Some((host, used)),
+            HostRule::Subdomain(_) if host.is_empty() => Some((server_name?.to_owned(), used)),
+            HostRule::Subdomain(_) => Some((format!("{}.{}", host, server_name?), used)),
         }
     }
 }
 
+/// Remove the port from a host unless the pattern it is matched against
+/// has one.
+fn strip_port(host: &str, keep_port: bool) -> &str {
+    match host.rfind(':') {
+        Some(index) if !keep_port && !host.ends_with(']') => &host[..index],
+        _ => host,
+    }
+}
 
-/// The map stores all the URL rules.
-#[derive(Clone, Debug)]
+
+/// The compiled routing index.  A `RegexSet` of all rule regexes finds
+/// the rules matching a path in one pass, only those are matched again
+/// with their own regex and converters, in the order they were added.
+#[derive(Debug)]
+struct RouteIndex {
+    /// `None` if the set could not be compiled, all rules are tried then.
+    set: Option<RegexSet>,
+    has_query_rules: bool,
+    endpoints: HashMap<String, Vec<usize>>,
+}
+
+impl RouteIndex {
+    fn new(rules: &[Rule]) -> RouteIndex {
+        let mut endpoints: HashMap<String, Vec<usize>> = HashMap::new();
+        for (i, rule) in rules.iter().enumerate() {
+            endpoints.entry(rule.endpoint.clone()).or_default().push(i);
+        }
+        RouteIndex {
+            set: RegexSet::new(rules.iter().map(|rule| rule.matcher.regex.as_str())).ok(),
+            has_query_rules: rules.iter().any(|rule| rule.matcher.matches_query),
+            endpoints: endpoints,
+        }
+    }
+
+    fn candidates(&self, text: &str) -> Candidates {
+        match self.set {
+            Some(ref set) => Candidates::Set(set.matches(text)),
+            None => Candidates::All,
+        }
+    }
+}
+
+/// The rules whose regex matched a text.
+enum Candidates {
+    All,
+    Set(SetMatches),
+}
+
+impl Candidates {
+    fn contains(&self, i: usize) -> bool {
+        match *self {
+            Candidates::All => true,
+            Candidates::Set(ref matches) => matches.matched(i),
+        }
+    }
+}
+
+
+/// The map stores all the URL rules and the converters they may use.
+#[derive(Debug)]
 pub struct Map {
     rules: Vec<Rule>,
+    converters: Converters,
+    index: RwLock<Option<Arc<RouteIndex>>>,
 }
 
 impl Default for Map {
     fn default() -> Map { Map::new() }
 }
 
+impl Clone for Map {
+    fn clone(&self) -> Map {
+        Map {
+            rules: self.rules.clone(),
+            converters: self.converters.clone(),
+            index: RwLock::new(None),
+        }
+    }
+}
+
 impl Map {
     pub fn new() -> Map {
-        Map { rules: vec![] }
+        Map {
+            rules: vec![],
+            converters: default_converters(),
+            index: RwLock::new(None),
+        }
     }
 
+    /// Register a converter under the given name.  Rules using the
+    /// converter must be added after it has been registered.
+    pub fn add_converter<C: Converter + 'static>(&mut self, name: &str, converter: C) {
+        self.converters.insert(name.to_owned(), Arc::new(converter));
+    }
+
+    /// Add a rule.  Rules are kept ordered by priority, see
+    /// `Converter::weight`, rules of equal priority are tried in the
+    /// order they were added.
     pub fn add(&mut self, rule: Rule) {
-        self.rules.push(rule);
+        let position = self.rules.iter()
+            .position(|other| rule.cmp_priority(other) == Ordering::Less)
+            .unwrap_or(self.rules.len());
+        self.rules.insert(position, rule);
+        *self.index.get_mut().unwrap() = None;
+    }
+
+    /// Check for rules that can never match because an earlier rule
+    /// matches the same urls with the same methods.
+    pub fn validate(&self) -> Result<(), Vec<RouteConflict>> {
+        let mut conflicts = Vec::new();
+        for (i, rule) in self.rules.iter().enumerate() {
+            for earlier in &self.rules[..i] {
+                if earlier.matcher.pattern != rule.matcher.pattern
+                        || earlier.matcher.matches_query != rule.matcher.matches_query
+                        || earlier.host_pattern() != rule.host_pattern() {
+                    continue;
+                }
+                let earlier_methods = earlier.explicit_methods();
+                let mut methods: Vec<Method> = rule.explicit_methods().into_iter()
+                    .filter(|method| earlier_methods.contains(method))
+                    .collect();
+                if !methods.is_empty() {
+                    methods.sort_by_key(|method| method.to_string());
+                    conflicts.push(RouteConflict::Ambiguous {
+                        rule: rule.rule_string(),
+                        endpoint: rule.endpoint.clone(),
+                        shadowed_by: earlier.endpoint.clone(),
+                        methods: methods,
+                    });
+                }
+            }
+        }
+        if conflicts.is_empty() { Ok(()) } else { Err(conflicts) }
+    }
+
+    /// The rules in the order they are matched.
+    pub fn iter_rules(&self) -> slice::Iter<Rule> {
+        self.rules.iter()
+    }
+
+    /// A table of all rules with their endpoint, methods and host, in the
+    /// order they are matched.
+    pub fn routing_table(&self) -> String {
+        let show_hosts = self.rules.iter().any(|rule| rule.host.is_some());
+        let mut rows = vec![vec![String::from("Endpoint"), String::from("Methods"), String::from("Rule")]];
+        if show_hosts {
+            rows[0].push(String::from("Host"));
+        }
+        for rule in &self.rules {
+            let methods: Vec<String> = rule.sorted_methods().iter().map(|method| method.to_string()).collect();
+            let mut row = vec![rule.endpoint.clone(), methods.join(", "), rule.rule_string()];
+            if show_hosts {
+                row.push(match rule.host {
+                    Some(ref host @ HostRule::Subdomain(_)) => format!("{} (subdomain)", host.template()),
+                    Some(ref host) => host.template().to_owned(),
+                    None => String::new(),
+                });
+            }
+            rows.push(row);
+        }
+        let widths: Vec<usize> = (0..rows[0].len())
+            .map(|i| rows.iter().map(|row| row[i].chars().count()).max().unwrap_or(0))
+            .collect();
+        let separator: Vec<String> = widths.iter().map(|&width| "-".repeat(width)).collect();
+        rows.insert(1, separator);
+        let mut table = String::new();
+        for row in rows {
+            let cells: Vec<String> = row.iter().zip(&widths)
+                .map(|(cell, &width)| format!("{:<width$}", cell, width = width))
+                .collect();
+            table.push_str(cells.join("  ").trim_end());
+            table.push('\n');
+        }
+        table
+    }
+
+    /// Compile the routing index now instead of on the first match.
+    /// Adding a rule afterwards discards the index again.
+    pub fn build_index(&self) {
+        self.index();
+    }
+
+    fn index(&self) -> Arc<RouteIndex> {
+        if let Some(ref index) = *self.index.read().unwrap() {
+            return index.clone();
+        }
+        let mut index = self.index.write().unwrap();
+        index.get_or_insert_with(|| Arc::new(RouteIndex::new(&self.rules))).clone()
     }
 
     pub fn bind(&self, host: String, path: String, query_string: Option<String>, method: Method) -> MapAdapter {
@@ -250,6 +976,7 @@ impl Map {
 pub struct MapAdapter<'m> {
     map: &'m Map,
     url_scheme: String,
+    server_name: Option<String>,
     host: String,
     path: String,
     query_string: Option<String>,
@@ -261,6 +988,7 @@ impl<'m> MapAdapter<'m> {
         MapAdapter {
             map: map,
             url_scheme: "http".to_owned(),
+            server_name: None,
             host: host,
             path: path,
             query_string: query_string,
@@ -268,6 +996,17 @@ impl<'m> MapAdapter<'m> {
         }
     }
 
+    /// Set the scheme used for redirects and external urls.
+    pub fn set_url_scheme(&mut self, url_scheme: &str) {
+        self.url_scheme = url_scheme.to_owned();
+    }
+
+    /// Set the name of the server, subdomain rules only match subdomains
+    /// of it.
+    pub fn set_server_name(&mut self, server_name: Option<String>) {
+        self.server_name = server_name;
+    }
+
     fn make_redirect_url(&self) -> String {
         let redirect_path = self.path.trim_left_matches('/').to_owned() + "/";
         let mut suffix = String::from("");
@@ -278,14 +1017,28 @@ impl<'m> MapAdapter<'m> {
     }
 
     pub fn matched(&self) -> MapAdapterMatched {
+        let index = self.map.index();
+        let path_candidates = index.candidates(&self.path);
+        // Rules matching the query string see the path with the query.
+        let query = match self.query_string {
+            Some(ref query_string) if index.has_query_rules => {
+                let query_path = self.path.to_string() + "?" + query_string;
+                let candidates = index.candidates(&query_path);
+                Some((query_path, candidates))
+            },
+            _ => None,
+        };
         let mut have_match_for = HashSet::new();
-        for rule in &self.map.rules {
+        for (i, rule) in self.map.rules.iter().enumerate() {
             let rule_view_args: ViewArgs;
-            let matched = if rule.matcher.matches_query {
-                if let Some(ref query_string) = self.query_string {
-                    rule.matched(&(self.path.to_string() + "?" + query_string))
-                } else { rule.matched(&self.path) }
-            } else { rule.matched(&self.path) };
+            let (text, candidates) = match query {
+                Some((ref query_path, ref candidates)) if rule.matcher.matches_query => (query_path, candidates),
+                _ => (&self.path, &path_candidates),
+            };
+            if !candidates.contains(i) {
+                continue;
+            }
+            let matched = rule.matched_host(&self.host, self.server_name.as_deref(), text);
             match matched {
                 Some(result) => {
                     match result {
@@ -317,9 +1070,13 @@ impl<'m> MapAdapter<'m> {
     }
 
     pub fn allowed_methods(&self) -> Vec<Method> {
+        let candidates = self.map.index().candidates(&self.path);
         let mut have_match_for = HashSet::new();
-        for rule in &self.map.rules {
-            if let Some(_) = rule.matched(&self.path) {
+        for (i, rule) in self.map.rules.iter().enumerate() {
+            if !candidates.contains(i) {
+                continue;
+            }
+            if let Some(_) = rule.matched_host(&self.host, self.server_name.as_deref(), &self.path) {
                 for method in &rule.methods {
                     have_match_for.insert(method.clone());
                 }
@@ -329,4 +1086,346 @@ impl<'m> MapAdapter<'m> {
         allowed_methods.extend(have_match_for.into_iter());
         allowed_methods
     }
+
+    /// Build a URL for the given endpoint.  Values that are not used by
+    /// the rule are appended as query string.  If `method` is given only
+    /// rules accepting that method are considered.  If `force_external` is
+    /// set, an absolute URL with scheme and host is returned.
+    pub fn build(&self, endpoint: &str, values: &ViewArgs, method: Option<Method>, force_external: bool)
+        -> Result<String, BuildError>
+    {
+        let index = self.map.index();
+        let rule_indices = index.endpoints.get(endpoint).map(|indices| &indices[..]).unwrap_or(&[]);
+        for rule in rule_indices.iter().map(|&i| &self.map.rules[i]) {
+            if let Some(ref method) = method {
+                if !rule.methods.contains(method) {
+                    continue;
+                }
+            }
+            let (host, host_used) = match rule.host {
+                Some(ref host_rule) => match host_rule.build(values, self.server_name.as_deref()) {
+                    Some((host, used)) => (Some(host), used),
+                    None => continue,
+                },
+                None => (None, HashSet::new()),
+            };
+            if let Some((mut url, mut used)) = rule.matcher.build(values) {
+                used.extend(host_used);
+                let mut extra: Vec<(&String, &String)> = values.iter()
+                    .filter(|&(k, _)| !used.contains(k))
+                    .collect();
+                if !extra.is_empty() {
+                    extra.sort();
+                    let query_string = form_urlencoded::Serializer::new(String::new())
+                        .extend_pairs(extra)
+                        .finish();
+                    url.push(if url.contains('?') { '&' } else { '?' });
+                    url.push_str(&query_string);
+                }
+                // Urls to other hosts are always external.
+                match host {
+                    Some(ref host) if !host.eq_ignore_ascii_case(&self.host)
+                            && !host.eq_ignore_ascii_case(strip_port(&self.host, false)) => {
+                        url = format!("{}://{}{}", self.url_scheme, host, url);
+                    },
+                    _ if force_external => {
+                        url = format!("{}://{}{}", self.url_scheme, self.host, url);
+                    },
+                    _ => (),
+                }
+                return Ok(url);
+            }
+        }
+        Err(BuildError::new(endpoint, method))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use hyper::method::Method;
+    use regex::Regex;
+
+    use http_errors::HTTPError;
+    use types::ViewArgs;
+    use super::{HostRule, IntoMatcher, Map, MapAdapterMatched, RouteConflict, Rule};
+
+    fn add<M: IntoMatcher>(map: &mut Map, rule: M, methods: &[Method], endpoint: &str) {
+        let matcher = rule.into_matcher(map);
+        map.add(Rule::new(matcher, methods, endpoint));
+    }
+
+    fn endpoints(map: &Map) -> Vec<&str> {
+        map.iter_rules().map(|rule| rule.endpoint.as_str()).collect()
+    }
+
+    fn matched_endpoint(map: &Map, path: &str) -> Option<String> {
+        match map.bind("localhost".to_owned(), path.to_owned(), None, Method::Get).matched() {
+            MapAdapterMatched::MatchedRule((rule, _)) => Some(rule.endpoint),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_converter_order() {
+        let mut map = Map::new();
+        add(&mut map, "/<value:path>", &[Method::Get], "path");
+        add(&mut map, "/<value>", &[Method::Get], "string");
+        add(&mut map, "/<value:int>", &[Method::Get], "int");
+        add(&mut map, "/static", &[Method::Get], "static");
+        assert_eq!(endpoints(&map), vec!["static", "int", "string", "path"]);
+        assert_eq!(matched_endpoint(&map, "/static").as_deref(), Some("static"));
+        assert_eq!(matched_endpoint(&map, "/42").as_deref(), Some("int"));
+        assert_eq!(matched_endpoint(&map, "/name").as_deref(), Some("string"));
+        assert_eq!(matched_endpoint(&map, "/a/b").as_deref(), Some("path"));
+    }
+
+    #[test]
+    fn test_static_segments_first() {
+        let mut map = Map::new();
+        add(&mut map, "/<section>/<page>", &[Method::Get], "page");
+        add(&mut map, "/<section>/index", &[Method::Get], "section_index");
+        add(&mut map, "/docs/<page>", &[Method::Get], "docs_page");
+        assert_eq!(endpoints(&map), vec!["docs_page", "section_index", "page"]);
+        assert_eq!(matched_endpoint(&map, "/docs/index").as_deref(), Some("docs_page"));
+        assert_eq!(matched_endpoint(&map, "/blog/index").as_deref(), Some("section_index"));
+    }
+
+    #[test]
+    fn test_longer_rule_first() {
+        let mut map = Map::new();
+        add(&mut map, "/users/", &[Method::Get], "users");
+        add(&mut map, "/users/<id:int>", &[Method::Get], "user");
+        add(&mut map, "/users/<id:int>/edit", &[Method::Get], "edit_user");
+        add(&mut map, "/users/<id:int>/<action>", &[Method::Get], "user_action");
+        assert_eq!(endpoints(&map), vec!["edit_user", "user_action", "user", "users"]);
+        assert_eq!(matched_endpoint(&map, "/users/1/edit").as_deref(), Some("edit_user"));
+        assert_eq!(matched_endpoint(&map, "/users/1/delete").as_deref(), Some("user_action"));
+        assert_eq!(matched_endpoint(&map, "/users/1").as_deref(), Some("user"));
+        assert_eq!(matched_endpoint(&map, "/users/").as_deref(), Some("users"));
+    }
+
+    #[test]
+    fn test_equal_priority_keeps_order() {
+        let mut map = Map::new();
+        add(&mut map, "/<a>", &[Method::Get], "first");
+        add(&mut map, "/<b>", &[Method::Get], "second");
+        assert_eq!(endpoints(&map), vec!["first", "second"]);
+    }
+
+    #[test]
+    fn test_regex_matchers_last() {
+        let mut map = Map::new();
+        add(&mut map, Regex::new(r"^/.*$").unwrap(), &[Method::Get], "catch_all");
+        add(&mut map, "/<value:path>", &[Method::Get], "path");
+        add(&mut map, "/", &[Method::Get], "index");
+        assert_eq!(endpoints(&map), vec!["path", "index", "catch_all"]);
+        assert_eq!(matched_endpoint(&map, "/a/b").as_deref(), Some("path"));
+        assert_eq!(matched_endpoint(&map, "/").as_deref(), Some("index"));
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut map = Map::new();
+        add(&mut map, "/users/<id:int>", &[Method::Get, Method::Post], "user");
+        add(&mut map, "/users/<user_id:int>", &[Method::Post, Method::Delete], "other_user");
+        add(&mut map, "/users/<name>", &[Method::Get], "user_by_name");
+        add(&mut map, "/users/<id:int>", &[Method::Put], "update_user");
+        let conflicts = map.validate().unwrap_err();
+        assert_eq!(conflicts.len(), 1);
+        match conflicts[0] {
+            RouteConflict::Ambiguous { ref rule, ref endpoint, ref shadowed_by, ref methods } => {
+                assert_eq!(rule, "/users/<user_id:int>");
+                assert_eq!(endpoint, "other_user");
+                assert_eq!(shadowed_by, "user");
+                assert_eq!(methods, &vec![Method::Post]);
+            },
+            ref conflict => panic!("unexpected conflict {}", conflict),
+        }
+
+        let mut map = Map::new();
+        add(&mut map, "/<value>", &[Method::Get], "string");
+        add(&mut map, "/<value>?q=<query>", &[Method::Get], "query");
+        add(&mut map, "/<value:int>", &[Method::Get], "int");
+        assert!(map.validate().is_ok());
+    }
+
+    fn sorted(methods: &[Method]) -> String {
+        let mut methods: Vec<String> = methods.iter().map(|method| method.to_string()).collect();
+        methods.sort();
+        methods.join(", ")
+    }
+
+    fn describe(matched: MapAdapterMatched) -> String {
+        match matched {
+            MapAdapterMatched::MatchedRule((rule, _)) => format!("rule {}", rule.endpoint),
+            MapAdapterMatched::MatchedRedirect((url, code)) => format!("redirect {} {}", code, url),
+            MapAdapterMatched::MatchedError(HTTPError::MethodNotAllowed(Some(methods))) => {
+                format!("405 {}", sorted(&methods))
+            },
+            MapAdapterMatched::MatchedError(error) => format!("error {:?}", error),
+        }
+    }
+
+    /// Match by trying every rule in order, without the index.
+    fn linear_matched(map: &Map, host: &str, path: &str, query_string: Option<&str>, method: &Method) -> String {
+        let mut allowed = HashSet::new();
+        for rule in map.iter_rules() {
+            let text = match query_string {
+                Some(query_string) if rule.matcher.matches_query => format!("{}?{}", path, query_string),
+                _ => path.to_owned(),
+            };
+            match rule.matched_host(host, Some("example.com"), &text) {
+                Some(Ok(_)) if rule.methods.contains(method) => return format!("rule {}", rule.endpoint),
+                Some(Ok(_)) => allowed.extend(rule.methods.iter().cloned()),
+                Some(Err(_)) => {
+                    let query = query_string.map(|query_string| format!("?{}", query_string)).unwrap_or_default();
+                    return format!("redirect 301 http://{}{}/{}", host, path, query);
+                },
+                None => (),
+            }
+        }
+        if allowed.is_empty() {
+            format!("error {:?}", HTTPError::NotFound)
+        } else {
+            format!("405 {}", sorted(&allowed.into_iter().collect::<Vec<_>>()))
+        }
+    }
+
+    fn linear_allowed_methods(map: &Map, host: &str, path: &str) -> String {
+        let methods: HashSet<Method> = map.iter_rules()
+            .filter(|rule| rule.matched_host(host, Some("example.com"), path).is_some())
+            .flat_map(|rule| rule.methods.iter().cloned())
+            .collect();
+        sorted(&methods.into_iter().collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn test_index_matches_linear_scan() {
+        let mut map = Map::new();
+        add(&mut map, "/", &[Method::Get], "index");
+        add(&mut map, "/users/", &[Method::Get, Method::Post], "users");
+        add(&mut map, "/users/<id:int>", &[Method::Get], "user");
+        add(&mut map, "/users/<id:int>", &[Method::Put, Method::Delete], "update_user");
+        add(&mut map, "/users/<id:int>/posts/", &[Method::Get], "posts");
+        add(&mut map, "/users/<name>", &[Method::Get], "user_by_name");
+        add(&mut map, "/search?q=<query>", &[Method::Get], "search_query");
+        add(&mut map, "/search", &[Method::Get, Method::Post], "search");
+        add(&mut map, "/files/<file:path>", &[Method::Get], "file");
+        add(&mut map, Regex::new(r"^/legacy/\d+$").unwrap(), &[Method::Get], "legacy");
+        let host_rule = HostRule::subdomain("<tenant>", &map);
+        let matcher = "/dashboard".into_matcher(&map);
+        map.add(Rule::new(matcher, &[Method::Get], "tenant_dashboard").with_host(host_rule));
+        let matcher = "/dashboard/".into_matcher(&map);
+        map.add(Rule::new(matcher, &[Method::Get], "dashboard"));
+
+        let paths = [
+            "/", "/users", "/users/", "/users/1", "/users/1/posts", "/users/1/posts/", "/users/bob",
+            "/users/bob/posts", "/search", "/files/a/b.txt", "/files/", "/legacy/12", "/legacy/x",
+            "/dashboard", "/dashboard/", "/missing",
+        ];
+        let methods = [Method::Get, Method::Head, Method::Post, Method::Put, Method::Delete, Method::Patch];
+        let query_strings = [None, Some("q=rust"), Some("page=2")];
+        for &host in &["example.com", "acme.example.com"] {
+            for &path in &paths {
+                for &query_string in &query_strings {
+                    for method in &methods {
+                        let mut adapter = map.bind(host.to_owned(), path.to_owned(),
+                                                   query_string.map(|q| q.to_owned()), method.clone());
+                        adapter.set_server_name(Some("example.com".to_owned()));
+                        assert_eq!(describe(adapter.matched()), linear_matched(&map, host, path, query_string, method),
+                                   "{} {} {}{:?}", method, host, path, query_string);
+                    }
+                }
+                let mut adapter = map.bind(host.to_owned(), path.to_owned(), None, Method::Options);
+                adapter.set_server_name(Some("example.com".to_owned()));
+                assert_eq!(sorted(&adapter.allowed_methods()), linear_allowed_methods(&map, host, path), "{} {}", host, path);
+            }
+        }
+    }
+
+    #[test]
+    fn test_index_invalidated_by_add() {
+        let mut map = Map::new();
+        add(&mut map, "/", &[Method::Get], "index");
+        map.build_index();
+        assert_eq!(matched_endpoint(&map, "/new"), None);
+        let adapter = map.bind("localhost".to_owned(), "/".to_owned(), None, Method::Get);
+        assert!(adapter.build("new", &ViewArgs::new(), None, false).is_err());
+
+        add(&mut map, "/new", &[Method::Get], "new");
+        assert_eq!(matched_endpoint(&map, "/new").as_deref(), Some("new"));
+        assert_eq!(matched_endpoint(&map, "/").as_deref(), Some("index"));
+        let adapter = map.bind("localhost".to_owned(), "/".to_owned(), None, Method::Get);
+        assert_eq!(adapter.build("new", &ViewArgs::new(), None, false).unwrap(), "/new");
+
+        // Clones compile their own index.
+        let mut clone = map.clone();
+        add(&mut clone, "/cloned", &[Method::Get], "cloned");
+        assert_eq!(matched_endpoint(&clone, "/cloned").as_deref(), Some("cloned"));
+        assert_eq!(matched_endpoint(&map, "/cloned"), None);
+    }
+
+    fn build_map() -> Map {
+        let mut map = Map::new();
+        add(&mut map, "/users/<name>", &[Method::Get], "user");
+        add(&mut map, "/users/<id:int>/edit", &[Method::Get], "edit_user");
+        add(&mut map, "/files/<path:path>", &[Method::Get], "file");
+        add(&mut map, "/search?q=<q>", &[Method::Get], "search");
+        add(&mut map, "/items/", &[Method::Get], "items");
+        add(&mut map, "/items/new", &[Method::Post], "items");
+        map
+    }
+
+    fn build(map: &Map, endpoint: &str, values: &[(&str, &str)], method: Option<Method>) -> Option<String> {
+        let mut view_args = ViewArgs::new();
+        for &(name, value) in values {
+            view_args.insert(name, value.to_owned());
+        }
+        map.bind("localhost:5000".to_owned(), "/".to_owned(), None, Method::Get)
+            .build(endpoint, &view_args, method, false)
+            .ok()
+    }
+
+    #[test]
+    fn test_build_percent_encoding() {
+        let map = build_map();
+        assert_eq!(build(&map, "user", &[("name", "a b/c?d#e%")], None).as_deref(),
+                   Some("/users/a%20b%2Fc%3Fd%23e%25"));
+        assert_eq!(build(&map, "user", &[("name", "café")], None).as_deref(), Some("/users/caf%C3%A9"));
+        assert_eq!(build(&map, "file", &[("path", "docs/a b.txt")], None).as_deref(), Some("/files/docs/a%20b.txt"));
+    }
+
+    #[test]
+    fn test_build_query_string() {
+        let map = build_map();
+        assert_eq!(build(&map, "user", &[("name", "pen"), ("tab", "a&b"), ("page", "2")], None).as_deref(),
+                   Some("/users/pen?page=2&tab=a%26b"));
+        assert_eq!(build(&map, "search", &[("q", "rust web")], None).as_deref(), Some("/search?q=rust%20web"));
+        assert_eq!(build(&map, "search", &[("q", "pen"), ("page", "2")], None).as_deref(), Some("/search?q=pen&page=2"));
+        assert_eq!(build(&map, "user", &[], None), None);
+        assert_eq!(build(&map, "missing", &[], None), None);
+    }
+
+    #[test]
+    fn test_build_method() {
+        let map = build_map();
+        assert!(build(&map, "items", &[], None).is_some());
+        assert_eq!(build(&map, "items", &[], Some(Method::Get)).as_deref(), Some("/items/"));
+        assert_eq!(build(&map, "items", &[], Some(Method::Post)).as_deref(), Some("/items/new"));
+        assert_eq!(build(&map, "items", &[], Some(Method::Delete)), None);
+    }
+
+    #[test]
+    fn test_build_external() {
+        let map = build_map();
+        let mut adapter = map.bind("localhost:5000".to_owned(), "/".to_owned(), None, Method::Get);
+        let mut view_args = ViewArgs::new();
+        view_args.insert("name", "pen".to_owned());
+        assert_eq!(adapter.build("user", &view_args, None, true).unwrap(), "http://localhost:5000/users/pen");
+        adapter.set_url_scheme("https");
+        assert_eq!(adapter.build("user", &view_args, None, true).unwrap(), "https://localhost:5000/users/pen");
+        assert_eq!(adapter.build("user", &view_args, None, false).unwrap(), "/users/pen");
+    }
 }
@@ -3,6 +3,11 @@ use hyper::server::Server;
 use app::Pen;
 
 pub fn run_server<A: ToSocketAddrs>(application: Pen, addr: A, threads: usize) {
+    if let Err(conflicts) = application.validate_routes() {
+        let conflicts: Vec<String> = conflicts.iter().map(|conflict| conflict.to_string()).collect();
+        panic!("invalid routes:\n{}", conflicts.join("\n"));
+    }
+    application.url_map.build_index();
     let server = Server::http(addr).unwrap();
     server.handle_threads(application, threads).unwrap();
 }
@@ -0,0 +1,764 @@
+//! This module implements sessions.  By default the session is stored
+//! in a signed cookie, its data is visible to the client but can not be
+//! modified without knowing the `SECRET_KEY` of the application.  With a
+//! `SessionStore` the data is kept on the server and the cookie only
+//! holds a random session id.
+
+use std::collections::{BTreeMap, HashMap};
+use std::collections::btree_map;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use base64;
+use url::form_urlencoded;
+
+use app::Pen;
+use wrappers::{Request, Response};
+use signing::{Signer, generate_token, timestamp};
+use cookies::CookieBuilder;
+
+/// The data of a session as kept by a `SessionStore`.  Keys starting
+/// with `_` are reserved for flags like `_permanent`, session keys
+/// starting with `_` are stored with another `_` in front.
+pub type SessionData = BTreeMap<String, String>;
+
+/// The default lifetime of permanent sessions, 31 days.
+pub const DEFAULT_PERMANENT_SESSION_LIFETIME: u64 = 31 * 24 * 60 * 60;
+
+const SESSION_SALT: &str = "pen.session";
+const PERMANENT_KEY: &str = "_permanent";
+const FLASH_SALT: &str = "pen.flash";
+
+/// The default time after which unused sessions are removed from a
+/// `SessionStore`, one day.
+pub const DEFAULT_SESSION_IDLE_TIMEOUT: u64 = 24 * 60 * 60;
+
+/// The default interval between two sweeps of a `SessionStore`.
+pub const DEFAULT_SESSION_SWEEP_INTERVAL: u64 = 10 * 60;
+
+/// The session of a request, a string map which is kept between requests
+/// of the same client.  The session is only written back to the client
+/// if it was modified.
+#[derive(Clone, Debug, Default)]
+pub struct Session {
+    data: SessionData,
+    modified: bool,
+    permanent: bool,
+    id: Option<String>,
+    regenerate_id: bool,
+}
+
+impl Session {
+    pub fn new() -> Session {
+        Session::default()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&String> {
+        self.data.get(key)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.data.contains_key(key)
+    }
+
+    pub fn set<V: Into<String>>(&mut self, key: &str, value: V) {
+        self.data.insert(key.to_owned(), value.into());
+        self.modified = true;
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        let value = self.data.remove(key);
+        if value.is_some() {
+            self.modified = true;
+        }
+        value
+    }
+
+    pub fn clear(&mut self) {
+        if !self.data.is_empty() {
+            self.modified = true;
+        }
+        self.data.clear();
+    }
+
+    pub fn iter(&self) -> btree_map::Iter<String, String> {
+        self.data.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Whether the session was changed during this request.
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+
+    /// Mark the session as modified, so it is written back even if it
+    /// was not changed.
+    pub fn mark_modified(&mut self) {
+        self.modified = true;
+    }
+
+    pub fn is_permanent(&self) -> bool {
+        self.permanent
+    }
+
+    /// A permanent session outlives the browser session, it expires after
+    /// `PERMANENT_SESSION_LIFETIME` seconds.
+    pub fn set_permanent(&mut self, permanent: bool) {
+        if self.permanent != permanent {
+            self.permanent = permanent;
+            self.modified = true;
+        }
+    }
+
+    /// The id of the session if it is kept in a `SessionStore`.
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    /// Move the session to a new id when it is saved, which should be done
+    /// whenever the privileges of the client change, for example on login.
+    pub fn regenerate_id(&mut self) {
+        self.regenerate_id = true;
+        self.modified = true;
+    }
+
+    fn to_data(&self) -> SessionData {
+        let mut data: SessionData = self.data.iter()
+            .map(|(key, value)| {
+                let key = if key.starts_with('_') { format!("_{}", key) } else { key.clone() };
+                (key, value.clone())
+            })
+            .collect();
+        if self.permanent {
+            data.insert(PERMANENT_KEY.to_owned(), "1".to_owned());
+        }
+        data
+    }
+
+    fn from_data(data: SessionData) -> Session {
+        let permanent = data.contains_key(PERMANENT_KEY);
+        let data = data.into_iter()
+            .filter_map(|(key, value)| {
+                if key.starts_with("__") {
+                    Some((key[1..].to_owned(), value))
+                } else if key.starts_with('_') {
+                    None
+                } else {
+                    Some((key, value))
+                }
+            })
+            .collect();
+        Session {
+            data: data,
+            permanent: permanent,
+            ..Session::default()
+        }
+    }
+
+    fn serialize(&self) -> String {
+        base64::encode_config(encode_data(&self.to_data()).as_bytes(), base64::URL_SAFE_NO_PAD)
+    }
+
+    fn deserialize(value: &str) -> Option<Session> {
+        let payload = base64::decode_config(value, base64::URL_SAFE_NO_PAD).ok()?;
+        Some(Session::from_data(decode_data(&payload)))
+    }
+}
+
+fn encode_data(data: &SessionData) -> String {
+    form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(data.iter())
+        .finish()
+}
+
+fn decode_data(payload: &[u8]) -> SessionData {
+    form_urlencoded::parse(payload).into_owned().collect()
+}
+
+/// A server side storage for sessions.  Stores are responsible for
+/// expiring sessions that were not used for `max_idle` seconds.
+pub trait SessionStore: Send + Sync {
+    /// Load the data of a session and mark it as used.  Returns `None` if
+    /// the session does not exist or was idle for more than `max_idle`
+    /// seconds.
+    fn load(&self, id: &str, max_idle: u64) -> Option<SessionData>;
+
+    /// Save the data of a session, creating it if it does not exist.
+    fn save(&self, id: &str, data: &SessionData) -> io::Result<()>;
+
+    /// Remove a session.
+    fn destroy(&self, id: &str) -> io::Result<()>;
+
+    /// Move a session to a new random id and return the new id.
+    fn regenerate_id(&self, id: &str) -> io::Result<String> {
+        let new_id = generate_token();
+        if let Some(data) = self.load(id, u64::MAX) {
+            self.save(&new_id, &data)?;
+        }
+        self.destroy(id)?;
+        Ok(new_id)
+    }
+
+    /// Remove all sessions that were idle for more than `max_idle` seconds.
+    fn sweep(&self, max_idle: u64) -> io::Result<()>;
+}
+
+/// Session ids are generated by `generate_token`, anything else sent by
+/// the client is ignored.
+fn is_valid_session_id(id: &str) -> bool {
+    id.len() == 43 && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// A session store keeping the sessions in memory.  Sessions are lost
+/// when the application is restarted.
+#[derive(Default)]
+pub struct MemorySessionStore {
+    sessions: Mutex<HashMap<String, (SessionData, u64)>>,
+}
+
+impl MemorySessionStore {
+    pub fn new() -> MemorySessionStore {
+        MemorySessionStore::default()
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    fn load(&self, id: &str, max_idle: u64) -> Option<SessionData> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = timestamp();
+        let expired = match sessions.get_mut(id) {
+            Some((_, used)) if used.saturating_add(max_idle) >= now => {
+                *used = now;
+                false
+            },
+            Some(_) => true,
+            None => return None,
+        };
+        if expired {
+            sessions.remove(id);
+            None
+        } else {
+            sessions.get(id).map(|(data, _)| data.clone())
+        }
+    }
+
+    fn save(&self, id: &str, data: &SessionData) -> io::Result<()> {
+        self.sessions.lock().unwrap().insert(id.to_owned(), (data.clone(), timestamp()));
+        Ok(())
+    }
+
+    fn destroy(&self, id: &str) -> io::Result<()> {
+        self.sessions.lock().unwrap().remove(id);
+        Ok(())
+    }
+
+    fn sweep(&self, max_idle: u64) -> io::Result<()> {
+        let now = timestamp();
+        self.sessions.lock().unwrap().retain(|_, (_, used)| used.saturating_add(max_idle) >= now);
+        Ok(())
+    }
+}
+
+/// A session store keeping every session in a file of a directory.  The
+/// modification time of a file is the last time the session was used.
+pub struct FileSystemSessionStore {
+    path: PathBuf,
+}
+
+impl FileSystemSessionStore {
+    /// Create a store in the given directory, it is created if needed.
+    pub fn new<P: Into<PathBuf>>(path: P) -> io::Result<FileSystemSessionStore> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+        Ok(FileSystemSessionStore {
+            path: path,
+        })
+    }
+
+    fn session_path(&self, id: &str) -> PathBuf {
+        self.path.join(format!("{}.session", id))
+    }
+
+    fn is_idle(path: &PathBuf, max_idle: u64) -> io::Result<bool> {
+        let modified = fs::metadata(path)?.modified()?;
+        let idle = SystemTime::now().duration_since(modified).map(|idle| idle.as_secs()).unwrap_or(0);
+        Ok(idle > max_idle)
+    }
+}
+
+impl SessionStore for FileSystemSessionStore {
+    fn load(&self, id: &str, max_idle: u64) -> Option<SessionData> {
+        if !is_valid_session_id(id) {
+            return None;
+        }
+        let path = self.session_path(id);
+        if FileSystemSessionStore::is_idle(&path, max_idle).ok()? {
+            let _ = fs::remove_file(&path);
+            return None;
+        }
+        let mut payload = Vec::new();
+        File::open(&path).and_then(|mut file| file.read_to_end(&mut payload)).ok()?;
+        let data = decode_data(&payload);
+        // Rewriting the file updates its modification time.
+        let _ = self.save(id, &data);
+        Some(data)
+    }
+
+    fn save(&self, id: &str, data: &SessionData) -> io::Result<()> {
+        if !is_valid_session_id(id) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid session id"));
+        }
+        let mut file = File::create(self.session_path(id))?;
+        file.write_all(encode_data(data).as_bytes())
+    }
+
+    fn destroy(&self, id: &str) -> io::Result<()> {
+        if !is_valid_session_id(id) {
+            return Ok(());
+        }
+        match fs::remove_file(self.session_path(id)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    fn sweep(&self, max_idle: u64) -> io::Result<()> {
+        for entry in fs::read_dir(&self.path)? {
+            let path = entry?.path();
+            if path.extension() == Some(OsStr::new("session")) &&
+                    FileSystemSessionStore::is_idle(&path, max_idle).unwrap_or(false) {
+                let _ = fs::remove_file(&path);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A signer using the `SECRET_KEY` of the application, `None` if it is
+/// not set.  Keys listed comma separated in `SECRET_KEY_FALLBACKS` are
+/// accepted for existing values, so the secret key can be rotated.
+pub(crate) fn app_signer(app: &Pen, salt: &str) -> Option<Signer> {
+    let secret_key: String = app.config.get("SECRET_KEY")?;
+    let mut signer = Signer::new(&secret_key, salt);
+    if let Some(fallbacks) = app.config.get::<String>("SECRET_KEY_FALLBACKS") {
+        for key in fallbacks.split(',').map(str::trim).filter(|key| !key.is_empty()) {
+            signer.add_fallback_key(key);
+        }
+    }
+    Some(signer)
+}
+
+pub(crate) fn session_cookie_name(app: &Pen) -> String {
+    app.config.get_or("SESSION_COOKIE_NAME", "session".to_owned())
+}
+
+fn permanent_session_lifetime(app: &Pen) -> u64 {
+    app.config.get_or("PERMANENT_SESSION_LIFETIME", DEFAULT_PERMANENT_SESSION_LIFETIME)
+}
+
+fn session_idle_timeout(app: &Pen) -> u64 {
+    app.config.get_or("SESSION_IDLE_TIMEOUT", DEFAULT_SESSION_IDLE_TIMEOUT)
+}
+
+/// Load the session from the session cookie.  Missing, tampered or
+/// expired cookies result in a new empty session.
+pub(crate) fn open_session(request: &Request) -> Session {
+    let app = request.app;
+    if let Some(ref store) = app.session_store {
+        return request.cookie(&session_cookie_name(app))
+            .and_then(|id| {
+                let data = store.load(&id, session_idle_timeout(app))?;
+                let mut session = Session::from_data(data);
+                session.id = Some(id);
+                Some(session)
+            })
+            .unwrap_or_default();
+    }
+    let signer = match app_signer(app, SESSION_SALT) {
+        Some(signer) => signer,
+        None => return Session::new(),
+    };
+    request.cookie(&session_cookie_name(app))
+        .and_then(|value| signer.unsign(&value, Some(permanent_session_lifetime(app))))
+        .and_then(|value| Session::deserialize(&value))
+        .unwrap_or_default()
+}
+
+/// A cookie with the configured session cookie attributes.
+fn session_cookie(app: &Pen, name: &str, value: &str, max_age: Option<u64>) -> CookieBuilder {
+    let mut cookie = CookieBuilder::new(name, value)
+        .path(&app.config.get_or("SESSION_COOKIE_PATH", "/".to_owned()))
+        .secure(app.config.get_boolean("SESSION_COOKIE_SECURE", false))
+        .http_only(app.config.get_boolean("SESSION_COOKIE_HTTPONLY", true));
+    if let Some(max_age) = max_age {
+        cookie = cookie.max_age(max_age);
+    }
+    if let Some(domain) = app.config.get::<String>("SESSION_COOKIE_DOMAIN") {
+        cookie = cookie.domain(&domain);
+    }
+    if let Some(same_site) = app.config.get::<String>("SESSION_COOKIE_SAMESITE").and_then(|value| value.parse().ok()) {
+        cookie = cookie.same_site(same_site);
+    }
+    cookie
+}
+
+/// A cookie deleting the session cookie.
+fn expired_session_cookie(app: &Pen, name: &str) -> CookieBuilder {
+    session_cookie(app, name, "", None).expired()
+}
+
+/// Remove idle sessions from the session store if the last sweep is older
+/// than `SESSION_SWEEP_INTERVAL` seconds.
+fn sweep_sessions(app: &Pen, store: &SessionStore) {
+    let now = timestamp();
+    let interval = app.config.get_or("SESSION_SWEEP_INTERVAL", DEFAULT_SESSION_SWEEP_INTERVAL);
+    {
+        let mut last_sweep = app.last_session_sweep.lock().unwrap();
+        if last_sweep.saturating_add(interval) > now {
+            return;
+        }
+        *last_sweep = now;
+    }
+    if let Err(e) = store.sweep(session_idle_timeout(app)) {
+        eprintln!("Couldn't sweep the session store: {}", e);
+    }
+}
+
+/// Save a session to the session store and set the session id cookie.
+fn save_stored_session(request: &Request, response: &mut Response, session: &Session, store: &SessionStore) -> io::Result<()> {
+    let app = request.app;
+    let name = session_cookie_name(app);
+    if session.is_empty() {
+        if let Some(ref id) = session.id {
+            store.destroy(id)?;
+            response.add_cookie(expired_session_cookie(app, &name));
+        }
+        return Ok(());
+    }
+    let id = match session.id {
+        Some(ref id) if session.regenerate_id => store.regenerate_id(id)?,
+        Some(ref id) => id.clone(),
+        None => generate_token(),
+    };
+    store.save(&id, &session.to_data())?;
+    let max_age = if session.is_permanent() { Some(permanent_session_lifetime(app)) } else { None };
+    response.add_cookie(session_cookie(app, &name, &id, max_age));
+    Ok(())
+}
+
+/// Write the session back to the response if it was modified, or if it
+/// is permanent and `SESSION_REFRESH_EACH_REQUEST` is set.  An empty
+/// session deletes the cookie.
+pub(crate) fn save_session(request: &Request, response: &mut Response) {
+    let app = request.app;
+    if let Some(ref store) = app.session_store {
+        sweep_sessions(app, &**store);
+    }
+    let refresh = app.config.get_boolean("SESSION_REFRESH_EACH_REQUEST", true);
+    let opened;
+    let session = match request.session {
+        Some(ref session) => session,
+        // The view did not use the session, it is only loaded to refresh
+        // the cookie.
+        None if refresh && request.cookie(&session_cookie_name(app)).is_some() => {
+            opened = open_session(request);
+            &opened
+        },
+        None => return,
+    };
+    let refresh = refresh && session.is_permanent() && !session.is_empty();
+    if !session.is_modified() && !refresh {
+        return;
+    }
+    if let Some(ref store) = app.session_store {
+        if let Err(e) = save_stored_session(request, response, session, &**store) {
+            eprintln!("Couldn't save the session of {}: {}", request.path(), e);
+        }
+        return;
+    }
+    let signer = match app_signer(app, SESSION_SALT) {
+        Some(signer) => signer,
+        None => {
+            eprintln!("The session of {} was modified but no SECRET_KEY is set, it is not saved", request.path());
+            return;
+        },
+    };
+    let name = session_cookie_name(app);
+    let cookie = if session.is_empty() {
+        expired_session_cookie(app, &name)
+    } else {
+        let max_age = if session.is_permanent() { Some(permanent_session_lifetime(app)) } else { None };
+        session_cookie(app, &name, &signer.sign(&session.serialize()), max_age)
+    };
+    response.add_cookie(cookie);
+}
+
+fn flash_cookie_name(app: &Pen) -> String {
+    app.config.get_or("FLASH_COOKIE_NAME", "_flashes".to_owned())
+}
+
+/// Load the flashed messages from the flash cookie.
+pub(crate) fn open_flashes(request: &Request) -> Vec<(String, String)> {
+    let app = request.app;
+    let signer = match app_signer(app, FLASH_SALT) {
+        Some(signer) => signer,
+        None => return Vec::new(),
+    };
+    request.cookie(&flash_cookie_name(app))
+        .and_then(|value| signer.unsign(&value, Some(permanent_session_lifetime(app))))
+        .and_then(|value| base64::decode_config(&value, base64::URL_SAFE_NO_PAD).ok())
+        .map(|payload| form_urlencoded::parse(&payload).into_owned().collect())
+        .unwrap_or_default()
+}
+
+/// Write the flashed messages back to the response if they changed.  The
+/// cookie is deleted once all messages were consumed.
+pub(crate) fn save_flashes(request: &Request, response: &mut Response) {
+    let flashes = match request.flashes {
+        Some((ref flashes, true)) => flashes,
+        _ => return,
+    };
+    let app = request.app;
+    let signer = match app_signer(app, FLASH_SALT) {
+        Some(signer) => signer,
+        None => {
+            eprintln!("A message was flashed on {} but no SECRET_KEY is set, it is not saved", request.path());
+            return;
+        },
+    };
+    let name = flash_cookie_name(app);
+    let cookie = if flashes.is_empty() {
+        expired_session_cookie(app, &name)
+    } else {
+        let payload = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(flashes.iter())
+            .finish();
+        let value = signer.sign(&base64::encode_config(payload.as_bytes(), base64::URL_SAFE_NO_PAD));
+        session_cookie(app, &name, &value, None)
+    };
+    response.add_cookie(cookie);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+
+    use app::Pen;
+    use signing::generate_token;
+    use testing::Client;
+    use wrappers::Request;
+    use super::{FileSystemSessionStore, MemorySessionStore, Session, SessionData, SessionStore,
+                is_valid_session_id};
+
+    fn data() -> SessionData {
+        let mut data = SessionData::new();
+        data.insert("user".to_owned(), "pen".to_owned());
+        data
+    }
+
+    fn store_dir(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("pen-sessions-{}-{}", name, ::std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        path
+    }
+
+    /// Make a session of a file store look unused for `seconds`.
+    fn age_session_file(store: &FileSystemSessionStore, id: &str, seconds: u64) {
+        let used = SystemTime::now() - Duration::from_secs(seconds);
+        File::options().write(true).open(store.session_path(id)).unwrap().set_modified(used).unwrap();
+    }
+
+    #[test]
+    fn test_reserved_keys() {
+        let mut session = Session::default();
+        session.set("_permanent", "user value");
+        session.set("__double", "2");
+        session.set("_", "3");
+        session.set("plain", "4");
+        assert!(!Session::from_data(session.to_data()).is_permanent());
+
+        session.set_permanent(true);
+        let data = session.to_data();
+        assert_eq!(data.get("_permanent").map(|value| value.as_str()), Some("1"));
+        for restored in &[Session::from_data(data), Session::deserialize(&session.serialize()).unwrap()] {
+            assert!(restored.is_permanent());
+            assert_eq!(restored.data, session.data);
+        }
+    }
+
+    #[test]
+    fn test_session_id_validation() {
+        assert!(is_valid_session_id(&generate_token()));
+        let too_long = format!("{}a", generate_token());
+        let with_dot = format!("{}.", &generate_token()[1..]);
+        for id in &["", "short", "../../etc/passwd", &too_long, &with_dot] {
+            assert!(!is_valid_session_id(id), "{}", id);
+        }
+    }
+
+    #[test]
+    fn test_file_store_path_traversal() {
+        let path = store_dir("traversal");
+        let store = FileSystemSessionStore::new(path.join("store")).unwrap();
+        let victim = path.join("victim.session");
+        File::create(&victim).unwrap();
+
+        assert!(store.save("../victim", &data()).is_err());
+        assert!(store.load("../victim", u64::MAX).is_none());
+        assert!(store.destroy("../victim").is_ok());
+        assert!(victim.exists());
+        assert_eq!(fs::read_dir(path.join("store")).unwrap().count(), 0);
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_memory_store_idle_sessions() {
+        let store = MemorySessionStore::new();
+        let (idle, used) = (generate_token(), generate_token());
+        store.save(&idle, &data()).unwrap();
+        store.save(&used, &data()).unwrap();
+        store.sessions.lock().unwrap().get_mut(&idle).unwrap().1 -= 120;
+        assert_eq!(store.load(&idle, 180), Some(data()));
+        store.sessions.lock().unwrap().get_mut(&idle).unwrap().1 -= 120;
+        assert_eq!(store.load(&idle, 60), None);
+        assert!(!store.sessions.lock().unwrap().contains_key(&idle));
+
+        store.save(&idle, &data()).unwrap();
+        store.sessions.lock().unwrap().get_mut(&idle).unwrap().1 -= 120;
+        store.sweep(60).unwrap();
+        let sessions = store.sessions.lock().unwrap();
+        assert!(!sessions.contains_key(&idle));
+        assert!(sessions.contains_key(&used));
+    }
+
+    #[test]
+    fn test_file_store_idle_sessions() {
+        let path = store_dir("idle");
+        let store = FileSystemSessionStore::new(&path).unwrap();
+        let (idle, used) = (generate_token(), generate_token());
+        store.save(&idle, &data()).unwrap();
+        store.save(&used, &data()).unwrap();
+        age_session_file(&store, &idle, 120);
+        assert_eq!(store.load(&idle, 180), Some(data()));
+        age_session_file(&store, &idle, 120);
+        assert_eq!(store.load(&idle, 60), None);
+        assert!(!store.session_path(&idle).exists());
+
+        store.save(&idle, &data()).unwrap();
+        age_session_file(&store, &idle, 120);
+        store.sweep(60).unwrap();
+        assert!(!store.session_path(&idle).exists());
+        assert!(store.session_path(&used).exists());
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    fn store_app<S: SessionStore + 'static>(store: S) -> Pen {
+        let mut app = Pen::new("/web/pen");
+        app.set_session_store(store);
+        app.get("/login", "login", |request: &mut Request| {
+            request.session().set("user", "pen");
+            request.session().regenerate_id();
+            "ok"
+        });
+        app.get("/user", "user", |request: &mut Request| {
+            request.session().get("user").cloned().unwrap_or_else(|| "anonymous".to_owned())
+        });
+        app.get("/logout", "logout", |request: &mut Request| {
+            request.session().clear();
+            "ok"
+        });
+        app
+    }
+
+    fn check_store_sessions(app: &Pen) {
+        let mut client = Client::new(app);
+        client.cookies.insert("session".to_owned(), "../../etc/passwd".to_owned());
+        assert_eq!(client.get("/user").text(), "anonymous");
+
+        client.get("/login");
+        let first_id = client.cookies["session"].clone();
+        assert!(is_valid_session_id(&first_id));
+        assert_eq!(client.get("/user").text(), "pen");
+
+        // Regenerating moves the data and forgets the old id.
+        client.get("/login");
+        let second_id = client.cookies["session"].clone();
+        assert_ne!(first_id, second_id);
+        assert_eq!(client.get("/user").text(), "pen");
+        client.cookies.insert("session".to_owned(), first_id);
+        assert_eq!(client.get("/user").text(), "anonymous");
+
+        // Clearing the session destroys it in the store.
+        client.cookies.insert("session".to_owned(), second_id.clone());
+        let response = client.get("/logout");
+        assert!(response.header("Set-Cookie").unwrap().contains("Max-Age=0"));
+        assert!(!client.cookies.contains_key("session"));
+        client.cookies.insert("session".to_owned(), second_id);
+        assert_eq!(client.get("/user").text(), "anonymous");
+    }
+
+    #[test]
+    fn test_memory_store() {
+        check_store_sessions(&store_app(MemorySessionStore::new()));
+    }
+
+    #[test]
+    fn test_file_store() {
+        let path = store_dir("app");
+        check_store_sessions(&store_app(FileSystemSessionStore::new(&path).unwrap()));
+        assert_eq!(fs::read_dir(&path).unwrap().count(), 0);
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    fn permanent_app() -> Pen {
+        let mut app = Pen::new("/web/pen");
+        app.config.set("SECRET_KEY", "secret");
+        app.get("/login/<permanent>", "login", |request: &mut Request| {
+            let permanent = request.view_args["permanent"] == "yes";
+            request.session().set("user", "pen");
+            request.session().set_permanent(permanent);
+            "ok"
+        });
+        app.get("/user", "user", |request: &mut Request| {
+            request.session().get("user").cloned().unwrap_or_else(|| "anonymous".to_owned())
+        });
+        app.get("/page", "page", |_: &mut Request| "page");
+        app
+    }
+
+    #[test]
+    fn test_refresh_permanent_session() {
+        let mut app = permanent_app();
+        let mut client = Client::new(&app);
+        client.get("/login/yes");
+        for path in &["/user", "/page"] {
+            let cookie = client.get(path).header("Set-Cookie").unwrap();
+            assert!(cookie.contains("Max-Age=2678400"), "{}", cookie);
+        }
+        assert_eq!(client.get("/user").text(), "pen");
+
+        client.get("/login/no");
+        assert!(client.get("/user").header("Set-Cookie").is_none());
+        assert!(client.get("/page").header("Set-Cookie").is_none());
+
+        app.config.set("SESSION_REFRESH_EACH_REQUEST", false);
+        let mut client = Client::new(&app);
+        client.get("/login/yes");
+        assert!(client.get("/user").header("Set-Cookie").is_none());
+        assert!(client.get("/page").header("Set-Cookie").is_none());
+    }
+}
@@ -0,0 +1,165 @@
+//! This module implements signing of values with the secret key of the
+//! application, so they can be stored on the client without being
+//! tampered with.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64;
+use hmac::{Hmac, Mac};
+use rand::{self, OsRng, Rng};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The current unix timestamp in seconds.
+pub fn timestamp() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+/// Generate a random url safe token with 256 bits of entropy, for
+/// session ids and similar secrets.
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    match OsRng::new() {
+        Ok(mut rng) => rng.fill_bytes(&mut bytes),
+        Err(_) => rand::thread_rng().fill_bytes(&mut bytes),
+    }
+    base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// Compare two strings in constant time, for comparing secrets.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |result, (x, y)| result | (x ^ y)) == 0
+}
+
+/// Signs values with HMAC-SHA256 and a timestamp.  Values are always
+/// signed with the first key, older keys are only used to verify values,
+/// which allows rotating keys without invalidating signed values.
+/// The salt separates values signed for different purposes.
+#[derive(Clone)]
+pub struct Signer {
+    keys: Vec<Vec<u8>>,
+    salt: String,
+}
+
+impl Signer {
+    pub fn new(secret_key: &str, salt: &str) -> Signer {
+        Signer {
+            keys: vec![secret_key.as_bytes().to_vec()],
+            salt: salt.to_owned(),
+        }
+    }
+
+    /// Add an old key that is still accepted when verifying values.
+    pub fn add_fallback_key(&mut self, secret_key: &str) {
+        self.keys.push(secret_key.as_bytes().to_vec());
+    }
+
+    fn mac(&self, key: &[u8], value: &str, timestamp: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_varkey(key).expect("HMAC accepts keys of any size");
+        mac.input(self.salt.as_bytes());
+        mac.input(b".");
+        mac.input(value.as_bytes());
+        mac.input(b".");
+        mac.input(timestamp.as_bytes());
+        mac
+    }
+
+    /// Sign a value, the result has the form `value.timestamp.signature`.
+    pub fn sign(&self, value: &str) -> String {
+        let timestamp = timestamp().to_string();
+        let signature = self.mac(&self.keys[0], value, &timestamp).result().code();
+        format!("{}.{}.{}", value, timestamp, base64::encode_config(&signature, base64::URL_SAFE_NO_PAD))
+    }
+
+    /// Verify a signed value and return the original value.  If `max_age`
+    /// is given, values signed more than `max_age` seconds ago are rejected.
+    pub fn unsign(&self, signed: &str, max_age: Option<u64>) -> Option<String> {
+        let mut parts = signed.rsplitn(3, '.');
+        let (signature, timestamp, value) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(signature), Some(timestamp), Some(value)) => (signature, timestamp, value),
+            _ => return None,
+        };
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).ok()?;
+        let valid = self.keys.iter().any(|key| self.mac(key, value, timestamp).verify(&signature).is_ok());
+        if !valid {
+            return None;
+        }
+        if let Some(max_age) = max_age {
+            let signed_at: u64 = timestamp.parse().ok()?;
+            if signed_at.saturating_add(max_age) < self::timestamp() {
+                return None;
+            }
+        }
+        Some(value.to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64;
+    use hmac::Mac;
+
+    use super::{Signer, timestamp};
+
+    /// Sign a value as if it had been signed at the given time.
+    fn sign_at(signer: &Signer, value: &str, signed_at: u64) -> String {
+        let signed_at = signed_at.to_string();
+        let signature = signer.mac(&signer.keys[0], value, &signed_at).result().code();
+        format!("{}.{}.{}", value, signed_at, base64::encode_config(&signature, base64::URL_SAFE_NO_PAD))
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let signer = Signer::new("secret", "salt");
+        let signed = signer.sign("a.value");
+        assert!(signed.starts_with("a.value."));
+        assert_eq!(signer.unsign(&signed, None), Some("a.value".to_string()));
+        assert_eq!(signer.unsign(&signed, Some(60)), Some("a.value".to_string()));
+        assert_eq!(signer.unsign(&signer.sign(""), None), Some(String::new()));
+    }
+
+    #[test]
+    fn test_tampered() {
+        let signer = Signer::new("secret", "salt");
+        let signed = signer.sign("value");
+        assert_eq!(signer.unsign(&signed.replacen("value", "other", 1), None), None);
+        assert_eq!(signer.unsign(&format!("{}x", signed), None), None);
+        assert_eq!(signer.unsign("value", None), None);
+        assert_eq!(signer.unsign("value.123.%%%", None), None);
+        assert_eq!(Signer::new("other", "salt").unsign(&signed, None), None);
+        assert_eq!(Signer::new("secret", "other").unsign(&signed, None), None);
+        let (value_and_timestamp, signature) = signed.split_at(signed.rfind('.').unwrap());
+        let moved = value_and_timestamp.replacen('.', ".1", 1);
+        assert_eq!(signer.unsign(&format!("{}{}", moved, signature), None), None);
+    }
+
+    #[test]
+    fn test_fallback_key() {
+        let old = Signer::new("old", "salt");
+        let mut signer = Signer::new("new", "salt");
+        signer.add_fallback_key("old");
+        assert_eq!(signer.unsign(&old.sign("value"), None), Some("value".to_string()));
+        // New values are signed with the first key only.
+        let signed = signer.sign("value");
+        assert_eq!(Signer::new("new", "salt").unsign(&signed, None), Some("value".to_string()));
+        assert_eq!(old.unsign(&signed, None), None);
+    }
+
+    #[test]
+    fn test_max_age() {
+        let signer = Signer::new("secret", "salt");
+        let signed = sign_at(&signer, "value", timestamp() - 100);
+        assert_eq!(signer.unsign(&signed, None), Some("value".to_string()));
+        assert_eq!(signer.unsign(&signed, Some(1000)), Some("value".to_string()));
+        assert_eq!(signer.unsign(&signed, Some(10)), None);
+        assert_eq!(signer.unsign(&signed, Some(u64::MAX)), Some("value".to_string()));
+        let signed = sign_at(&signer, "value", u64::MAX);
+        assert_eq!(signer.unsign(&signed, Some(10)), Some("value".to_string()));
+        let signed = sign_at(&signer, "value", 0);
+        assert_eq!(signer.unsign(&signed, Some(10)), None);
+    }
+}
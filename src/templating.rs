@@ -0,0 +1,283 @@
+//! This module implements template rendering.  The templates are loaded
+//! from the `template_folder` of the application and the module of the
+//! current request and rendered by a pluggable `TemplateEngine`.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "templates")] use std::collections::HashMap;
+#[cfg(feature = "templates")] use std::fs;
+#[cfg(feature = "templates")] use std::sync::RwLock;
+#[cfg(feature = "templates")] use std::time::SystemTime;
+
+#[cfg(feature = "templates")] use handlebars::Handlebars;
+#[cfg(feature = "templates")] use serde::{Serialize, Serializer};
+
+use wrappers::{Request, Response};
+use types::{PenResult, UserError};
+use helpers::safe_join;
+use csrf::csrf_token;
+#[cfg(feature = "templates")] use helpers::escape;
+
+/// A value that can be used in a template context.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+}
+
+/// The context a template is rendered with.
+pub type Context = BTreeMap<String, Value>;
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Value { Value::Bool(value) }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Value { Value::Int(value) }
+}
+
+impl From<i32> for Value {
+    fn from(value: i32) -> Value { Value::Int(i64::from(value)) }
+}
+
+impl From<u32> for Value {
+    fn from(value: u32) -> Value { Value::Int(i64::from(value)) }
+}
+
+impl From<usize> for Value {
+    fn from(value: usize) -> Value { Value::Int(value as i64) }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Value { Value::Float(value) }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Value { Value::String(value) }
+}
+
+impl<'a> From<&'a str> for Value {
+    fn from(value: &'a str) -> Value { Value::String(value.to_owned()) }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(value: Vec<T>) -> Value { Value::List(value.into_iter().map(Into::into).collect()) }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Value {
+        match value {
+            Some(value) => value.into(),
+            None => Value::Null,
+        }
+    }
+}
+
+impl From<Context> for Value {
+    fn from(value: Context) -> Value { Value::Map(value) }
+}
+
+#[cfg(feature = "templates")]
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            Value::Null => serializer.serialize_unit(),
+            Value::Bool(value) => serializer.serialize_bool(value),
+            Value::Int(value) => serializer.serialize_i64(value),
+            Value::Float(value) => serializer.serialize_f64(value),
+            Value::String(ref value) => serializer.serialize_str(value),
+            Value::List(ref values) => serializer.collect_seq(values),
+            Value::Map(ref values) => serializer.collect_map(values),
+        }
+    }
+}
+
+/// A template engine renders template files.
+pub trait TemplateEngine: Send + Sync {
+    /// Render the template file at `path`.  The same template name can
+    /// resolve to different files for different modules, so engines that
+    /// cache compiled templates should key them by path.  If `reload` is
+    /// set they should pick up changes of the template file.
+    fn render(&self, path: &Path, context: &Context, reload: bool) -> Result<String, UserError>;
+}
+
+/// The default template engine, using handlebars.  Templates are compiled
+/// once and cached, values are escaped with `helpers::escape`.
+#[cfg(feature = "templates")]
+pub struct HandlebarsEngine {
+    registry: RwLock<Handlebars>,
+    modified: RwLock<HashMap<String, Option<SystemTime>>>,
+}
+
+#[cfg(feature = "templates")]
+impl HandlebarsEngine {
+    pub fn new() -> HandlebarsEngine {
+        let mut registry = Handlebars::new();
+        registry.register_escape_fn(escape);
+        HandlebarsEngine {
+            registry: RwLock::new(registry),
+            modified: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn load(&self, key: &str, path: &Path, reload: bool) -> Result<(), UserError> {
+        let modified = fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+        if let Some(cached) = self.modified.read().unwrap().get(key) {
+            if !reload || *cached == modified {
+                return Ok(());
+            }
+        }
+        self.registry.write().unwrap().register_template_file(key, path)
+            .map_err(|e| UserError::new(format!("couldn't load template {}: {}", path.display(), e)))?;
+        self.modified.write().unwrap().insert(key.to_owned(), modified);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "templates")]
+impl Default for HandlebarsEngine {
+    fn default() -> HandlebarsEngine { HandlebarsEngine::new() }
+}
+
+#[cfg(feature = "templates")]
+impl TemplateEngine for HandlebarsEngine {
+    fn render(&self, path: &Path, context: &Context, reload: bool) -> Result<String, UserError> {
+        let key = path.to_string_lossy().into_owned();
+        self.load(&key, path, reload)?;
+        self.registry.read().unwrap().render(&key, context)
+            .map_err(|e| UserError::new(format!("couldn't render template {}: {}", path.display(), e)))
+    }
+}
+
+/// The folders templates are looked up in, the template folder of the
+/// module of the request first.
+fn template_folders(request: &Request) -> Vec<PathBuf> {
+    let mut folders = Vec::new();
+    if let Some(module) = request.module_name().and_then(|name| request.app.modules.get(&name)) {
+        if let Some(ref template_folder) = module.template_folder {
+            folders.push(PathBuf::from(&module.root_path).join(template_folder));
+        }
+    }
+    folders.push(PathBuf::from(&request.app.root_path).join(&request.app.template_folder));
+    folders
+}
+
+/// The `request` value available in templates.
+fn request_context(request: &Request) -> Value {
+    let mut args = Context::new();
+    for (key, value) in request.args().iter() {
+        args.insert(key.clone(), value.clone().into());
+    }
+    let mut view_args = Context::new();
+    for (key, value) in request.view_args.iter() {
+        view_args.insert(key.clone(), value.clone().into());
+    }
+    let mut context = Context::new();
+    context.insert("method".to_owned(), request.method().to_string().into());
+    context.insert("path".to_owned(), request.path().into());
+    context.insert("full_path".to_owned(), request.full_path().into());
+    context.insert("url".to_owned(), request.url().into());
+    context.insert("host".to_owned(), request.host().into());
+    context.insert("endpoint".to_owned(), request.endpoint().into());
+    context.insert("module_name".to_owned(), request.module_name().into());
+    context.insert("args".to_owned(), args.into());
+    context.insert("view_args".to_owned(), view_args.into());
+    Value::Map(context)
+}
+
+/// Render a template from the template folder with the given context.
+/// The current request is available in the template as `request`.
+/// Templates are reloaded on change in debug mode.
+pub fn render_template(request: &Request, name: &str, context: &Context) -> PenResult {
+    let engine = match request.app.template_engine {
+        Some(ref engine) => engine,
+        None => return Err(UserError::new("no template engine configured").into()),
+    };
+    let path = template_folders(request).into_iter()
+        .filter_map(|folder| folder.to_str().and_then(|folder| safe_join(folder, name)))
+        .find(|path| path.is_file());
+    let path = match path {
+        Some(path) => path,
+        None => return Err(UserError::new(format!("template {} not found", name)).into()),
+    };
+    let mut context = context.clone();
+    context.insert("request".to_owned(), request_context(request));
+    if let Some(token) = csrf_token(request) {
+        context.insert("csrf_token".to_owned(), token.into());
+    }
+    let body = engine.render(&path, &context, request.app.is_debug())?;
+    let mut response = Response::from(body);
+    response.set_content_type("text/html");
+    Ok(response)
+}
+
+#[cfg(all(test, feature = "templates"))]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, SystemTime};
+
+    use app::Pen;
+    use testing::Client;
+    use wrappers::Request;
+    use super::{Context, render_template};
+
+    fn app_root(name: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("pen-templates-{}-{}", name, ::std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("templates")).unwrap();
+        root
+    }
+
+    fn write_template(root: &Path, content: &str, modified: SystemTime) {
+        let mut file = File::create(root.join("templates").join("page.html")).unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        file.set_modified(modified).unwrap();
+    }
+
+    fn page(request: &mut Request) -> ::types::PenResult {
+        let mut context = Context::new();
+        let name: Option<&str> = request.args().get("name");
+        context.insert("name".to_owned(), name.into());
+        render_template(request, "page.html", &context)
+    }
+
+    #[test]
+    fn test_escaping() {
+        let root = app_root("escaping");
+        write_template(&root, "{{name}} {{{name}}} {{request.args.name}}", SystemTime::now());
+        let mut app = Pen::new(root.to_str().unwrap());
+        app.get("/", "page", page);
+
+        let response = Client::new(&app).get("/?name=%3Cscript%3E%22%26");
+        assert_eq!(response.header("Content-Type").unwrap(), "text/html; charset=utf-8");
+        assert_eq!(response.text(), "&lt;script&gt;&quot;&amp; <script>\"& &lt;script&gt;&quot;&amp;");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_reload_in_debug_mode() {
+        let root = app_root("reload");
+        let now = SystemTime::now();
+        write_template(&root, "first", now - Duration::from_secs(60));
+        let mut app = Pen::new(root.to_str().unwrap());
+        app.get("/", "page", page);
+        assert_eq!(Client::new(&app).get("/").text(), "first");
+
+        write_template(&root, "second", now - Duration::from_secs(30));
+        assert_eq!(Client::new(&app).get("/").text(), "first");
+        app.config.set("DEBUG", true);
+        assert_eq!(Client::new(&app).get("/").text(), "second");
+
+        write_template(&root, "third", now);
+        assert_eq!(Client::new(&app).get("/").text(), "third");
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,244 @@
+//! This module implements an in-memory test client.  Requests are run
+//! through `Pen::handle_request` without opening any sockets.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Cursor, Read, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use hyper::buffer::BufReader;
+use hyper::header::{Headers, Cookie, SetCookie, Host, TransferEncoding};
+use hyper::method::Method;
+use hyper::net::NetworkStream;
+use hyper::server::request::Request as HttpRequest;
+use url::form_urlencoded;
+
+use app::Pen;
+use wrappers::{Request, ResponseBody};
+use httputils::get_name_by_http_code;
+
+/// A network stream that reads a prepared request from memory and
+/// discards everything written to it.
+struct MemoryStream {
+    input: Cursor<Vec<u8>>,
+    peer_addr: SocketAddr,
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl NetworkStream for MemoryStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(self.peer_addr)
+    }
+
+    fn set_read_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_write_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A request to be sent with the test `Client`.
+pub struct TestRequest {
+    pub method: Method,
+    pub path: String,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    cookies: BTreeMap<String, String>,
+}
+
+impl TestRequest {
+    pub fn new(method: Method, path: &str) -> TestRequest {
+        TestRequest {
+            method: method,
+            path: path.to_string(),
+            headers: Headers::new(),
+            body: Vec::new(),
+            cookies: BTreeMap::new(),
+        }
+    }
+
+    /// Set a raw header.
+    pub fn header(mut self, name: &str, value: &str) -> TestRequest {
+        self.headers.set_raw(name.to_string(), vec![value.as_bytes().to_vec()]);
+        self
+    }
+
+    pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> TestRequest {
+        self.body = body.into();
+        self
+    }
+
+    /// Set an url encoded form as body.
+    pub fn form(self, fields: &[(&str, &str)]) -> TestRequest {
+        let body = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(fields)
+            .finish();
+        self.header("Content-Type", "application/x-www-form-urlencoded").body(body)
+    }
+
+    /// Send a cookie with this request only.
+    pub fn cookie(mut self, name: &str, value: &str) -> TestRequest {
+        self.cookies.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    fn to_bytes(&self, cookies: &BTreeMap<String, String>) -> Vec<u8> {
+        let mut headers = self.headers.clone();
+        if !headers.has::<Host>() {
+            headers.set_raw("Host", vec![b"localhost".to_vec()]);
+        }
+        let mut all_cookies = cookies.clone();
+        all_cookies.extend(self.cookies.clone());
+        if !all_cookies.is_empty() {
+            let cookie = all_cookies.into_iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+            headers.set(Cookie(cookie));
+        }
+        if !self.body.is_empty() && !headers.has::<TransferEncoding>() {
+            headers.set_raw("Content-Length", vec![self.body.len().to_string().into_bytes()]);
+        }
+        let mut bytes = format!("{} {} HTTP/1.1\r\n{}\r\n", self.method, self.path, headers).into_bytes();
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+}
+
+/// A fully buffered response returned by the test `Client`.
+pub struct TestResponse {
+    pub status_code: u16,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+}
+
+impl TestResponse {
+    pub fn status_name(&self) -> &str {
+        match get_name_by_http_code(self.status_code) {
+            Some(name) => name,
+            None => "UNKNOWN",
+        }
+    }
+
+    /// Get the first value of a header as string.
+    pub fn header(&self, name: &str) -> Option<String> {
+        self.headers.get_raw(name)
+            .and_then(|values| values.first())
+            .map(|value| String::from_utf8_lossy(value).into_owned())
+    }
+
+    /// The body decoded as UTF-8.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+impl fmt::Debug for TestResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<Pen TestResponse [{}]>", self.status_code)
+    }
+}
+
+/// A client to test an application.  Cookies set by responses are kept
+/// and sent with the following requests.
+pub struct Client<'a> {
+    pub app: &'a Pen,
+    pub cookies: BTreeMap<String, String>,
+}
+
+impl<'a> Client<'a> {
+    pub fn new(app: &'a Pen) -> Client<'a> {
+        Client {
+            app: app,
+            cookies: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&mut self, path: &str) -> TestResponse {
+        self.open(TestRequest::new(Method::Get, path))
+    }
+
+    pub fn head(&mut self, path: &str) -> TestResponse {
+        self.open(TestRequest::new(Method::Head, path))
+    }
+
+    pub fn post<B: Into<Vec<u8>>>(&mut self, path: &str, body: B) -> TestResponse {
+        self.open(TestRequest::new(Method::Post, path).body(body))
+    }
+
+    pub fn put<B: Into<Vec<u8>>>(&mut self, path: &str, body: B) -> TestResponse {
+        self.open(TestRequest::new(Method::Put, path).body(body))
+    }
+
+    pub fn delete(&mut self, path: &str) -> TestResponse {
+        self.open(TestRequest::new(Method::Delete, path))
+    }
+
+    /// Run a request through the application.
+    pub fn open(&mut self, test_request: TestRequest) -> TestResponse {
+        let method = test_request.method.clone();
+        let mut stream = MemoryStream {
+            input: Cursor::new(test_request.to_bytes(&self.cookies)),
+            peer_addr: "127.0.0.1:0".parse().unwrap(),
+        };
+        let peer_addr = stream.peer_addr;
+        let response = {
+            let stream_ref: &mut NetworkStream = &mut stream;
+            let mut reader = BufReader::new(stream_ref);
+            let http_request = HttpRequest::new(&mut reader, peer_addr).expect("invalid test request");
+            let mut request = Request::new(self.app, http_request).expect("invalid test request");
+            self.app.handle_request(&mut request)
+        };
+
+        let mut body = Vec::new();
+        if method != Method::Head {
+            if let Some(mut response_body) = response.body {
+                response_body.write_body(&mut ResponseBody::new(&mut body)).expect("couldn't buffer body");
+            }
+        }
+        let test_response = TestResponse {
+            status_code: response.status_code,
+            headers: response.headers,
+            body: body,
+        };
+        self.store_cookies(&test_response.headers);
+        test_response
+    }
+
+    fn store_cookies(&mut self, headers: &Headers) {
+        if let Some(&SetCookie(ref cookies)) = headers.get() {
+            for cookie in cookies {
+                let mut parts = cookie.split(';');
+                let pair = parts.next().unwrap_or("");
+                let (name, value) = match pair.find('=') {
+                    Some(index) => (pair[..index].trim(), pair[index + 1..].trim()),
+                    None => continue,
+                };
+                let expired = parts.any(|attribute| {
+                    let attribute = attribute.trim().to_lowercase();
+                    attribute == "max-age=0" || attribute.starts_with("max-age=-")
+                });
+                if expired {
+                    self.cookies.remove(name);
+                } else {
+                    self.cookies.insert(name.to_string(), value.to_string());
+                }
+            }
+        }
+    }
+}
@@ -1,15 +1,17 @@
-use std::collections::HashMap;
 use std::error;
 use std::convert;
 use std::error::Error;
 use std::fmt;
 
 use wrappers::{Request, Response};
+use routing::BuildError;
+use formparser::FormError;
 pub use http_errors::HTTPError;
 
 pub use self::PenError::{
     PenHTTPError,
-    PenUserError
+    PenUserError,
+    PenFormError,
 };
 
 #[derive(Clone, Debug)]
@@ -41,6 +43,7 @@ impl error::Error for UserError {
 pub enum PenError {
     PenHTTPError(HTTPError),
     PenUserError(UserError),
+    PenFormError(FormError),
 }
 
 impl convert::From<HTTPError> for PenError {
@@ -55,11 +58,24 @@ impl convert::From<UserError> for PenError {
     }
 }
 
+impl convert::From<FormError> for PenError {
+    fn from(err: FormError) -> PenError {
+        PenFormError(err)
+    }
+}
+
+impl convert::From<BuildError> for PenError {
+    fn from(err: BuildError) -> PenError {
+        PenUserError(UserError::new(err.description()))
+    }
+}
+
 impl fmt::Display for PenError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             PenHTTPError(ref err) => f.write_str(err.description()),
             PenUserError(ref err) => f.write_str(err.description()),
+            PenFormError(ref err) => fmt::Display::fmt(err, f),
         }
     }
 }
@@ -69,6 +85,7 @@ impl error::Error for PenError {
         match *self {
             PenHTTPError(ref err) => err.description(),
             PenUserError(ref err) => err.description(),
+            PenFormError(ref err) => err.description(),
         }
     }
 
@@ -76,17 +93,19 @@ impl error::Error for PenError {
         match *self {
             PenHTTPError(ref err) => Some(&*err as &error::Error),
             PenUserError(_) => None,
+            PenFormError(ref err) => Some(err as &error::Error),
         }
     }
 }
 
 pub type PenResult = Result<Response, PenError>;
 
-pub type ViewArgs = HashMap<String, String>;
-pub type ViewFunc = fn(&mut Request) -> PenResult;
+pub use datastructures::ViewArgs;
+pub type ViewFunc = Fn(&mut Request) -> PenResult + Send + Sync;
 
 pub type HTTPErrorHandler = Fn(HTTPError) -> PenResult + Send + Sync;
 pub type UserErrorHandler = Fn(UserError) -> PenResult + Send + Sync;
+pub type FormErrorHandler = Fn(FormError) -> PenResult + Send + Sync;
 
 pub type BeforeRequestFunc = Fn(&mut Request) -> Option<PenResult> + Send + Sync;
 pub type AfterRequestFunc = Fn(&Request, &mut Response) + Send + Sync;
@@ -1,16 +1,17 @@
 use std::fmt;
-use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::fs::File;
 use std::io::{self, Read, Write, Take};
 use std::convert;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::env;
+use std::path::PathBuf;
 
 use hyper;
 use hyper::server::request::Request as HttpRequest;
 use hyper::uri::RequestUri::{AbsolutePath, AbsoluteUri, Authority, Star};
-use hyper::header::{Headers, ContentLength, ContentType, Cookie, Host};
-use hyper::mime::Mime;
+use hyper::header::{Headers, ContentLength, ContentType, Cookie, SetCookie, Host};
+use hyper::mime::{Mime, TopLevel, SubLevel};
 use hyper::method::Method;
 use hyper::http::h1::HttpReader;
 use hyper::net::NetworkStream;
@@ -24,11 +25,23 @@ use app::Pen;
 use datastructures::MultiDict;
 use httputils::{get_name_by_http_code, get_content_type, get_host_value};
 use httputils::get_status_from_code;
-use routing::{Rule, MapAdapterMatched, MapAdapter};
-use types::ViewArgs;
-use http_errors::HTTPError;
-use formparser::FormDataParser;
+use routing::{Rule, MapAdapterMatched, MapAdapter, BuildError};
+use types::{ViewArgs, PenResult, PenError, PenHTTPError};
+use http_errors::{HTTPError, NotFound};
+use formparser::{FormDataParser, FormError};
+use multipart::{Multipart, remove_temp_files};
+use sessions::{Session, open_session};
+use cookies::{CookieBuilder, parse_cookie};
 use lazycell::LazyCell;
+#[cfg(feature = "json")] use serde::Serialize;
+#[cfg(feature = "json")] use serde::de::DeserializeOwned;
+#[cfg(feature = "json")] use serde_json;
+#[cfg(feature = "json")] use types::UserError;
+#[cfg(feature = "json")] use http_errors::{BadRequest, RequestEntityTooLarge, UnsupportedMediaType};
+
+/// The default maximum size of a JSON request body, one MiB.
+#[cfg(feature = "json")]
+const DEFAULT_JSON_MAX_LENGTH: u64 = 1024 * 1024;
 
 pub struct Request<'r, 'a, 'b: 'a> {
     pub app: &'r Pen,
@@ -43,9 +56,19 @@ pub struct Request<'r, 'a, 'b: 'a> {
     pub extensions_data: TypeMap,
     pub host: Host,
     body: RefCell<HttpReader<&'a mut BufReader<&'b mut NetworkStream>>>,
+    body_read: Cell<u64>,
+    body_too_large: Cell<bool>,
+    /// Bytes of the body which were read ahead and are read again first.
+    unread: RefCell<Vec<u8>>,
     args: LazyCell<MultiDict<String>>,
     form: LazyCell<MultiDict<String>>,
     files: LazyCell<MultiDict<FilePart>>,
+    form_error: LazyCell<Option<FormError>>,
+    temp_files: RefCell<Vec<PathBuf>>,
+    pub(crate) session: Option<Session>,
+    /// The flashed messages as `(category, message)` and whether they
+    /// were changed during this request.
+    pub(crate) flashes: Option<(Vec<(String, String)>, bool)>,
 }
 
 impl<'r, 'a, 'b: 'a> Request<'r, 'a, 'b> {
@@ -73,24 +96,34 @@ impl<'r, 'a, 'b: 'a> Request<'r, 'a, 'b> {
             headers: headers,
             url: url,
             url_rule: None,
-            view_args: HashMap::new(),
+            view_args: ViewArgs::new(),
             routing_redirect: None,
             routing_error: None,
             extensions_data: TypeMap::new(),
             body: RefCell::new(body),
+            body_read: Cell::new(0),
+            body_too_large: Cell::new(false),
+            unread: RefCell::new(Vec::new()),
             host: host,
             args: LazyCell::new(),
             form: LazyCell::new(),
             files: LazyCell::new(),
+            form_error: LazyCell::new(),
+            temp_files: RefCell::new(Vec::new()),
+            session: None,
+            flashes: None,
         })
     }
 
     pub fn url_adapter(&self) -> MapAdapter {
-        self.app.url_map.bind(self.host(), self.path(), self.query_string(), self.method())
+        let mut url_adapter = self.app.url_map.bind(self.host(), self.path(), self.query_string(), self.method());
+        url_adapter.set_url_scheme(&self.scheme());
+        url_adapter.set_server_name(self.app.config.get("SERVER_NAME"));
+        url_adapter
     }
 
     pub fn match_request(&mut self) {
-        let url_adapter = self.app.url_map.bind(self.host(), self.path(), self.query_string(), self.method());
+        let url_adapter = self.url_adapter();
         match url_adapter.matched() {
             MapAdapterMatched::MatchedRule((rule, view_args)) => {
                 self.url_rule = Some(rule);
@@ -105,6 +138,30 @@ impl<'r, 'a, 'b: 'a> Request<'r, 'a, 'b> {
         }
     }
 
+    /// Build a URL for the given endpoint, relative to the host.
+    /// Endpoints starting with a dot are relative to the module of the
+    /// current request, so `.index` may refer to `admin.index`.
+    pub fn url_for(&self, endpoint: &str, values: &ViewArgs) -> Result<String, BuildError> {
+        self.url_adapter().build(&self.resolve_endpoint(endpoint), values, None, false)
+    }
+
+    /// Build an absolute URL for the given endpoint, using the scheme
+    /// and host of this request.
+    pub fn external_url_for(&self, endpoint: &str, values: &ViewArgs) -> Result<String, BuildError> {
+        self.url_adapter().build(&self.resolve_endpoint(endpoint), values, None, true)
+    }
+
+    fn resolve_endpoint(&self, endpoint: &str) -> String {
+        if endpoint.starts_with('.') {
+            match self.module_name() {
+                Some(module_name) => module_name + endpoint,
+                None => endpoint[1..].to_string(),
+            }
+        } else {
+            endpoint.to_string()
+        }
+    }
+
     pub fn endpoint(&self) -> Option<String> {
         match self.url_rule {
             Some(ref rule) => Some(rule.endpoint.clone()),
@@ -143,31 +200,207 @@ impl<'r, 'a, 'b: 'a> Request<'r, 'a, 'b> {
 
     fn load_form_data(&self) {
         if self.form.filled() { return; }
-        let (form, files) = match self.content_type() {
+        let result = match self.content_type() {
             Some(ContentType(mimetype)) => {
                 let parser = FormDataParser::new();
-                parser.parse(&mut *self.body.borrow_mut(), &self.headers, &mimetype)
+                parser.parse(&mut BodyReader(self), &self.headers, &mimetype)
             },
-            None => (MultiDict::new(), MultiDict::new()),
+            None => Ok((MultiDict::new(), MultiDict::new())),
+        };
+        let (form, files, form_error) = match result {
+            Ok((form, files)) => (form, files, None),
+            Err(_) if self.body_too_large() => (MultiDict::new(), MultiDict::new(), Some(FormError::SizeExceeded)),
+            Err(e) => (MultiDict::new(), MultiDict::new(), Some(e)),
         };
         self.form.fill(form).expect("This was checked to be empty!");
         self.files.fill(files).expect("This was checked to be empty!");
+        self.form_error.fill(form_error).expect("This was checked to be empty!");
     }
 
+    /// The form fields, empty if the body could not be parsed.
     pub fn form(&self) -> &MultiDict<String> {
         self.load_form_data();
         self.form.borrow().expect("This is always checked to be filled.")
     }
 
+    /// The uploaded files, empty if the body could not be parsed.
     pub fn files(&self) -> &MultiDict<FilePart> {
         self.load_form_data();
         self.files.borrow().expect("This is always checked to be filled.")
     }
 
+    /// The form fields, or the reason the body could not be parsed.
+    /// Returning the error from a view passes it to the form error handler.
+    pub fn try_form(&self) -> Result<&MultiDict<String>, FormError> {
+        match self.form_error() {
+            Some(e) => Err(e.clone()),
+            None => Ok(self.form()),
+        }
+    }
+
+    /// The uploaded files, or the reason the body could not be parsed.
+    pub fn try_files(&self) -> Result<&MultiDict<FilePart>, FormError> {
+        match self.form_error() {
+            Some(e) => Err(e.clone()),
+            None => Ok(self.files()),
+        }
+    }
+
+    /// Read a multipart body part by part instead of loading it with
+    /// `form` and `files`.  The body can only be read once, so this fails
+    /// with `FormError::BodyConsumed` after the form was accessed.
+    pub fn multipart(&self) -> Result<Multipart, FormError> {
+        if self.body_read.get() != self.unread.borrow().len() as u64 {
+            return Err(FormError::BodyConsumed);
+        }
+        let content_type = self.content_type();
+        let temp_dir = self.app.config.get::<String>("UPLOAD_TEMP_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(env::temp_dir);
+        let multipart = Multipart::new(Box::new(BodyReader(self)), content_type.as_ref().map(|content_type| &content_type.0),
+                                       temp_dir, &self.temp_files, &self.body_too_large)?;
+        match self.app.config.get("MAX_UPLOAD_FILE_SIZE") {
+            Some(max_file_size) => Ok(multipart.max_file_size(max_file_size)),
+            None => Ok(multipart),
+        }
+    }
+
+    /// The value of the named form field of a multipart body, if it is
+    /// found within the first `max_read` bytes.  The bytes read are put
+    /// back, so the body can still be read with `multipart` or `form`.
+    pub(crate) fn peek_multipart_field(&self, name: &str, max_read: u64) -> Option<String> {
+        if !self.is_multipart() {
+            return None;
+        }
+        let content_type = self.content_type();
+        let mut recorded = Vec::new();
+        let value = {
+            let reader = RecordingReader { reader: BodyReader(self), recorded: &mut recorded, limit: max_read };
+            Multipart::new(Box::new(reader), content_type.as_ref().map(|content_type| &content_type.0),
+                           env::temp_dir(), &self.temp_files, &self.body_too_large)
+                .ok()
+                .and_then(|mut multipart| {
+                    while let Ok(Some(mut part)) = multipart.next_part() {
+                        if part.name() == Some(name) && !part.is_file() {
+                            return part.text().ok();
+                        }
+                    }
+                    None
+                })
+        };
+        let mut unread = self.unread.borrow_mut();
+        let rest = ::std::mem::replace(&mut *unread, recorded);
+        unread.extend(rest);
+        value
+    }
+
+    /// Remove the temporary files saved by `multipart` uploads.
+    pub(crate) fn remove_temp_files(&self) {
+        remove_temp_files(&self.temp_files);
+    }
+
+    fn form_error(&self) -> Option<&FormError> {
+        self.load_form_data();
+        self.form_error.borrow().expect("This is always checked to be filled.").as_ref()
+    }
+
+    /// Check if the mimetype is `multipart/form-data`.
+    pub fn is_multipart(&self) -> bool {
+        matches!(self.content_type(), Some(ContentType(Mime(TopLevel::Multipart, SubLevel::FormData, _))))
+    }
+
+    /// Check if the mimetype indicates JSON data, either `application/json`
+    /// or `application/*+json`.
+    pub fn is_json(&self) -> bool {
+        match self.content_type() {
+            Some(ContentType(Mime(TopLevel::Application, SubLevel::Json, _))) => true,
+            Some(ContentType(Mime(TopLevel::Application, SubLevel::Ext(ref sub), _))) => sub.ends_with("+json"),
+            _ => false,
+        }
+    }
+
+    /// Parse the body as JSON.  Returns `UnsupportedMediaType` if the
+    /// request is not JSON, `RequestEntityTooLarge` if the body exceeds
+    /// the `JSON_MAX_LENGTH` config value or the maximum content length
+    /// and `BadRequest` if parsing fails.
+    /// The body can only be read once.
+    #[cfg(feature = "json")]
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, HTTPError> {
+        if !self.is_json() {
+            return Err(UnsupportedMediaType);
+        }
+        let mut limit = self.app.config.get_or("JSON_MAX_LENGTH", DEFAULT_JSON_MAX_LENGTH);
+        if let Some(max_content_length) = self.max_content_length() {
+            limit = limit.min(max_content_length);
+        }
+        if let Some(&ContentLength(length)) = self.headers.get() {
+            if length > limit {
+                return Err(RequestEntityTooLarge);
+            }
+        }
+        let mut body = Vec::new();
+        if BodyReader(self).take(limit + 1).read_to_end(&mut body).is_err() {
+            return Err(if self.body_too_large() { RequestEntityTooLarge } else { BadRequest });
+        }
+        if body.len() as u64 > limit {
+            return Err(RequestEntityTooLarge);
+        }
+        serde_json::from_slice(&body).map_err(|_| BadRequest)
+    }
+
     pub fn headers(&self) -> &Headers {
         &self.headers
     }
 
+    /// The maximum size of the request body, the limit set for the
+    /// endpoint with `Pen::set_max_content_length` or the
+    /// `MAX_CONTENT_LENGTH` config value.
+    pub fn max_content_length(&self) -> Option<u64> {
+        let endpoint_limit = self.endpoint().and_then(|endpoint| self.app.max_content_lengths.get(&endpoint).cloned());
+        endpoint_limit.or_else(|| self.app.config.get("MAX_CONTENT_LENGTH"))
+    }
+
+    /// Whether the `Content-Length` of the request exceeds the maximum.
+    pub fn exceeds_max_content_length(&self) -> bool {
+        match (self.max_content_length(), self.headers.get::<ContentLength>()) {
+            (Some(max_content_length), Some(&ContentLength(length))) => length > max_content_length,
+            _ => false,
+        }
+    }
+
+    /// Whether reading the body was stopped because it exceeded the
+    /// maximum content length.
+    pub fn body_too_large(&self) -> bool {
+        self.body_too_large.get()
+    }
+
+    /// Read from the body, failing once more than the maximum content
+    /// length was read.  This also limits bodies without `Content-Length`.
+    fn read_body(&self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.body_too_large.get() {
+            return Err(body_too_large_error());
+        }
+        {
+            let mut unread = self.unread.borrow_mut();
+            if !unread.is_empty() {
+                let len = unread.len().min(buf.len());
+                buf[..len].copy_from_slice(&unread[..len]);
+                unread.drain(..len);
+                return Ok(len);
+            }
+        }
+        let read = self.body.borrow_mut().read(buf)?;
+        let total = self.body_read.get() + read as u64;
+        self.body_read.set(total);
+        match self.max_content_length() {
+            Some(max_content_length) if total > max_content_length => {
+                self.body_too_large.set(true);
+                Err(body_too_large_error())
+            },
+            _ => Ok(read),
+        }
+    }
+
     pub fn path(&self) -> String {
         self.url.path().to_owned()
     }
@@ -192,16 +425,39 @@ impl<'r, 'a, 'b: 'a> Request<'r, 'a, 'b> {
         self.headers.get()
     }
 
+    /// Get the decoded value of a cookie sent with the request.
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        let cookies = self.cookies()?;
+        cookies.iter()
+            .filter_map(|cookie| parse_cookie(cookie))
+            .find(|(cookie_name, _)| cookie_name == name)
+            .map(|(_, value)| value)
+    }
+
     pub fn method(&self) -> Method {
         self.method.clone()
     }
 
+    /// The session of the client, loaded from the session cookie on
+    /// first access.
+    pub fn session(&mut self) -> &mut Session {
+        if self.session.is_none() {
+            self.session = Some(open_session(self));
+        }
+        self.session.as_mut().unwrap()
+    }
+
     pub fn remote_addr(&self) -> SocketAddr {
         self.remote_addr
     }
 
+    /// The url scheme, taken from the `PREFERRED_URL_SCHEME` config.
+    pub fn scheme(&self) -> String {
+        self.app.config.get_or("PREFERRED_URL_SCHEME", String::from("http"))
+    }
+
     pub fn host_url(&self) -> String {
-        "http://".to_owned() + &self.host() + "/"
+        self.scheme() + "://" + &self.host() + "/"
     }
 
     pub fn url(&self) -> String {
@@ -221,10 +477,41 @@ impl<'r, 'a, 'b: 'a> fmt::Debug for Request<'r, 'a, 'b> {
 
 impl<'r, 'a, 'b: 'a> Read for Request<'r, 'a, 'b> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.body.borrow_mut().read(buf)
+        self.read_body(buf)
     }
 }
 
+/// Reads the body of a shared request.
+struct BodyReader<'x, 'r: 'x, 'a: 'x, 'b: 'a>(&'x Request<'r, 'a, 'b>);
+
+impl<'x, 'r, 'a, 'b> Read for BodyReader<'x, 'r, 'a, 'b> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read_body(buf)
+    }
+}
+
+/// Reads the body of a shared request and keeps a copy of what was read,
+/// the body seems to end after `limit` bytes.
+struct RecordingReader<'x, 'y, 'r: 'x, 'a: 'x, 'b: 'a> {
+    reader: BodyReader<'x, 'r, 'a, 'b>,
+    recorded: &'y mut Vec<u8>,
+    limit: u64,
+}
+
+impl<'x, 'y, 'r, 'a, 'b> Read for RecordingReader<'x, 'y, 'r, 'a, 'b> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.limit.saturating_sub(self.recorded.len() as u64);
+        let len = (buf.len() as u64).min(remaining) as usize;
+        let read = self.reader.read(&mut buf[..len])?;
+        self.recorded.extend_from_slice(&buf[..read]);
+        Ok(read)
+    }
+}
+
+fn body_too_large_error() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "the request body exceeds the maximum content length")
+}
+
 pub struct ResponseBody<'a>(Box<Write + 'a>);
 
 impl<'a> ResponseBody<'a> {
@@ -309,6 +596,19 @@ impl Response {
         }
     }
 
+    /// Create a response with the JSON representation of the value.
+    #[cfg(feature = "json")]
+    pub fn json<T: Serialize>(value: &T) -> PenResult {
+        match serde_json::to_vec(value) {
+            Ok(body) => {
+                let mut response = Response::from(body);
+                response.set_content_type("application/json");
+                Ok(response)
+            },
+            Err(e) => Err(UserError::new(format!("couldn't serialize JSON: {}", e)).into()),
+        }
+    }
+
     pub fn status_name(&self) -> &str {
         match get_name_by_http_code(self.status_code) {
             Some(name) => name,
@@ -340,8 +640,26 @@ impl Response {
         self.headers.set(content_length);
     }
 
-    pub fn set_cookie(&mut self, cookie: hyper::header::SetCookie) {
-        self.headers.set(cookie);
+    /// Add the cookies of a `Set-Cookie` header, keeping the cookies that
+    /// were already set.
+    pub fn set_cookie(&mut self, cookie: SetCookie) {
+        let SetCookie(new_cookies) = cookie;
+        let mut cookies = match self.headers.get::<SetCookie>() {
+            Some(SetCookie(cookies)) => cookies.clone(),
+            None => Vec::new(),
+        };
+        cookies.extend(new_cookies);
+        self.headers.set(SetCookie(cookies));
+    }
+
+    /// Add a cookie, keeping the cookies that were already set.
+    pub fn add_cookie(&mut self, cookie: CookieBuilder) {
+        self.set_cookie(SetCookie(vec![cookie.to_string()]));
+    }
+
+    /// Delete a cookie with path `/` on the client.
+    pub fn delete_cookie(&mut self, name: &str) {
+        self.add_cookie(CookieBuilder::new(name, "").path("/").expired());
     }
 
     pub fn write(self, request_method: Method, mut res: hyper::server::Response) {
@@ -414,3 +732,74 @@ impl convert::From<File> for Response {
         response
     }
 }
+
+/// Conversion of the return value of a view into a response.
+///
+/// Besides `Response` itself, strings and byte vectors become a `200`
+/// response, `(u16, T)` and `(u16, Headers, T)` override the status code
+/// and add headers, `None` becomes a `404` and errors are passed on to
+/// the error handlers.
+pub trait Responder {
+    fn respond(self) -> PenResult;
+}
+
+impl Responder for Response {
+    fn respond(self) -> PenResult {
+        Ok(self)
+    }
+}
+
+impl Responder for String {
+    fn respond(self) -> PenResult {
+        Ok(self.into())
+    }
+}
+
+impl Responder for &'static str {
+    fn respond(self) -> PenResult {
+        Ok(self.into())
+    }
+}
+
+impl Responder for Vec<u8> {
+    fn respond(self) -> PenResult {
+        Ok(self.into())
+    }
+}
+
+impl<T: Responder> Responder for (u16, T) {
+    fn respond(self) -> PenResult {
+        let (status_code, body) = self;
+        let mut response = body.respond()?;
+        response.status_code = status_code;
+        Ok(response)
+    }
+}
+
+impl<T: Responder> Responder for (u16, Headers, T) {
+    fn respond(self) -> PenResult {
+        let (status_code, headers, body) = self;
+        let mut response = body.respond()?;
+        response.status_code = status_code;
+        response.headers.extend(headers.iter());
+        Ok(response)
+    }
+}
+
+impl<T: Responder> Responder for Option<T> {
+    fn respond(self) -> PenResult {
+        match self {
+            Some(body) => body.respond(),
+            None => Err(PenHTTPError(NotFound)),
+        }
+    }
+}
+
+impl<T: Responder, E: Into<PenError>> Responder for Result<T, E> {
+    fn respond(self) -> PenResult {
+        match self {
+            Ok(body) => body.respond(),
+            Err(e) => Err(e.into()),
+        }
+    }
+}